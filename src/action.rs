@@ -0,0 +1,598 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::{Component, Path, PathBuf},
+    process::exit,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cli::Action, formatting::Group};
+
+/// A change made by `--action`, as recorded in the journal. Links replace a
+/// file that was moved to the trash first, so they can be undone too, unless
+/// there was no trash to move it to and it was `unlinked` instead.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Entry {
+    Delete {
+        path: PathBuf,
+    },
+    Trash {
+        path: PathBuf,
+    },
+    Move {
+        path: PathBuf,
+        to: PathBuf,
+    },
+    Hardlink {
+        path: PathBuf,
+        keeper: PathBuf,
+        #[serde(default, skip_serializing_if = "is_false")]
+        unlinked: bool,
+    },
+    Symlink {
+        path: PathBuf,
+        keeper: PathBuf,
+        #[serde(default, skip_serializing_if = "is_false")]
+        unlinked: bool,
+    },
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// A change to make to an image that isn't kept.
+pub struct Step<'a> {
+    pub path: &'a Path,
+    pub keeper: &'a Path,
+    /// Where the image is moved to, for `move:<dir>`.
+    pub to: Option<PathBuf>,
+}
+
+/// Whether two files are on the same filesystem, which hard links can't
+/// cross.
+#[cfg(unix)]
+fn same_filesystem(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(std::fs::metadata(a)?.dev() == std::fs::metadata(b)?.dev())
+}
+
+#[cfg(not(unix))]
+fn same_filesystem(_a: &Path, _b: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// What identifies a file, whichever path it's reached by.
+#[cfg(unix)]
+type FileId = (u64, u64);
+
+#[cfg(not(unix))]
+type FileId = PathBuf;
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> io::Result<FileId> {
+    use std::os::unix::fs::MetadataExt;
+
+    let stat = std::fs::metadata(path)?;
+    Ok((stat.dev(), stat.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(path: &Path) -> io::Result<FileId> {
+    std::fs::canonicalize(path)
+}
+
+/// Whether two paths are already links to the same file.
+fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    Ok(file_id(a)? == file_id(b)?)
+}
+
+/// Where `move:<dir>` puts a file. The whole path is kept below the
+/// directory, so files with the same name don't collide and it's clear where
+/// they came from.
+fn move_destination(dir: &Path, path: &Path) -> io::Result<PathBuf> {
+    let absolute = std::path::absolute(path)?;
+    let relative: PathBuf = absolute
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    Ok(dir.join(relative))
+}
+
+/// Renames a file, or copies it and removes the original when it's going to
+/// another filesystem.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+/// Works out what to do with each image that isn't kept, group by group, or
+/// why it has to be left alone. An image that is kept in any group is never
+/// touched, which matters with `--reference` where an image can be in several
+/// groups, and neither is the same file under another path.
+pub fn plan<'a>(groups: &[Group<'a>], action: &Action) -> Vec<Vec<Result<Step<'a>, String>>> {
+    let keepers: HashSet<&Path> = groups.iter().filter_map(|group| group.keeper).collect();
+    let keeper_ids: HashMap<FileId, &Path> = keepers
+        .iter()
+        .filter_map(|keeper| Some((file_id(keeper).ok()?, *keeper)))
+        .collect();
+    let mut seen: HashSet<&Path> = HashSet::new();
+    let mut seen_ids: HashSet<FileId> = HashSet::new();
+    let mut plans = Vec::new();
+
+    for group in groups {
+        let mut steps = Vec::new();
+        let Some(keeper) = group.keeper else {
+            plans.push(steps);
+            continue;
+        };
+        for path in &group.members {
+            if keepers.contains(path) || !seen.insert(*path) {
+                continue;
+            }
+            let id = file_id(path).ok();
+            let kept = id.as_ref().and_then(|id| keeper_ids.get(id)).copied();
+            if id.is_some_and(|id| !seen_ids.insert(id)) {
+                continue;
+            }
+            let step = || -> Result<Step<'a>, String> {
+                let error = |e: io::Error| format!("{}: {}", path.display(), e);
+                if let Some(kept) = kept {
+                    return Err(format!(
+                        "{}: The same file as {}, which is kept",
+                        path.display(),
+                        kept.display()
+                    ));
+                }
+                let mut to = None;
+                match action {
+                    Action::Hardlink => {
+                        if !same_filesystem(path, keeper).map_err(error)? {
+                            return Err(format!(
+                                "{}: On a different filesystem than {}, so it can't be hard linked",
+                                path.display(),
+                                keeper.display()
+                            ));
+                        }
+                    }
+                    Action::Move(dir) => {
+                        let destination = move_destination(dir, path).map_err(error)?;
+                        if destination.exists() {
+                            return Err(format!(
+                                "{}: {} already exists",
+                                path.display(),
+                                destination.display()
+                            ));
+                        }
+                        to = Some(destination);
+                    }
+                    Action::Delete | Action::Trash | Action::Symlink => (),
+                }
+                Ok(Step { path, keeper, to })
+            };
+            steps.push(step());
+        }
+        plans.push(steps);
+    }
+
+    plans
+}
+
+/// Makes a change, returning what to record in the journal. A link that
+/// fails after its file went to the trash is still recorded as trashed, so
+/// the file can be restored. Paths are recorded as absolute paths, so the
+/// changes can be undone from any directory.
+///
+/// Links are made under a temporary name next to the file first, and renamed
+/// over it once the file is in the trash. Where there's no trash, such as on
+/// a headless server, the rename replaces the file without it.
+fn perform(step: &Step, action: &Action) -> Result<Entry, (Option<Entry>, String)> {
+    let failed = |entry: Option<Entry>, e: &dyn std::fmt::Display| (entry, e.to_string());
+    let path = std::path::absolute(step.path).map_err(|e| failed(None, &e))?;
+    match action {
+        Action::Delete => std::fs::remove_file(&path)
+            .map(|_| Entry::Delete { path })
+            .map_err(|e| failed(None, &e)),
+        Action::Trash => trash::delete(&path)
+            .map(|_| Entry::Trash { path })
+            .map_err(|e| failed(None, &e)),
+        Action::Move(_) => {
+            let to = step.to.as_deref().expect("Move without a destination");
+            let to = std::path::absolute(to).map_err(|e| failed(None, &e))?;
+            move_file(&path, &to)
+                .map(|_| Entry::Move { path, to })
+                .map_err(|e| failed(None, &e))
+        }
+        Action::Hardlink | Action::Symlink => {
+            let keeper = std::fs::canonicalize(step.keeper).map_err(|e| failed(None, &e))?;
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let temp = path.with_file_name(format!(".{}.simagef-link", name));
+            let linked = if *action == Action::Hardlink {
+                std::fs::hard_link(&keeper, &temp)
+            } else {
+                symlink(&keeper, &temp)
+            };
+            linked.map_err(|e| failed(None, &e))?;
+
+            let unlinked = match trash::delete(&path) {
+                Ok(()) => false,
+                Err(e) => {
+                    eprintln!(
+                        "{}: Unable to move to the trash, replacing it without: {}",
+                        path.display(),
+                        e
+                    );
+                    true
+                }
+            };
+            if let Err(e) = std::fs::rename(&temp, &path) {
+                std::fs::remove_file(&temp).ok();
+                let trashed = (!unlinked).then(|| Entry::Trash { path: path.clone() });
+                return Err(failed(trashed, &e));
+            }
+            Ok(if *action == Action::Hardlink {
+                Entry::Hardlink {
+                    path,
+                    keeper,
+                    unlinked,
+                }
+            } else {
+                Entry::Symlink {
+                    path,
+                    keeper,
+                    unlinked,
+                }
+            })
+        }
+    }
+}
+
+fn describe(step: &Step, action: &Action) -> String {
+    match action {
+        Action::Delete => format!("delete {}", step.path.display()),
+        Action::Trash => format!("trash {}", step.path.display()),
+        Action::Move(_) => format!(
+            "move {} to {}",
+            step.path.display(),
+            step.to
+                .as_ref()
+                .expect("Move without a destination")
+                .display()
+        ),
+        Action::Hardlink => format!(
+            "hard link {} to {}",
+            step.path.display(),
+            step.keeper.display()
+        ),
+        Action::Symlink => format!(
+            "symlink {} to {}",
+            step.path.display(),
+            step.keeper.display()
+        ),
+    }
+}
+
+fn default_journal() -> Option<PathBuf> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the Unix epoch")
+        .as_secs();
+    platform_dirs::AppDirs::new(Some("simagef"), false)
+        .map(|dirs| dirs.data_dir.join(format!("journal-{}.jsonl", time)))
+}
+
+fn open_journal(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    File::options().create(true).append(true).open(path)
+}
+
+/// Applies `--action` to the images that aren't kept in each group, or only
+/// lists what it would do unless `apply` is set. The list goes to stderr,
+/// since stdout has the groups. Each change is added to the journal as soon
+/// as it's made.
+pub fn run_action(groups: &[Group], action: &Action, apply: bool, journal: Option<&Path>) {
+    let steps: Vec<_> = plan(groups, action).into_iter().flatten().collect();
+
+    if !apply {
+        let mut count = 0;
+        for step in &steps {
+            match step {
+                Ok(step) => {
+                    eprintln!("Would {}", describe(step, action));
+                    count += 1;
+                }
+                Err(e) => eprintln!("Skipping {}", e),
+            }
+        }
+        eprintln!(
+            "Dry run, {} files would be changed. Use --apply to make these changes.",
+            count
+        );
+        return;
+    }
+
+    let journal_path = match journal.map(Path::to_path_buf).or_else(default_journal) {
+        Some(path) => path,
+        None => {
+            eprintln!("Unable to find a directory for the journal, use --journal to give one.");
+            exit(1);
+        }
+    };
+    let mut journal = match open_journal(&journal_path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("{}: {}", journal_path.display(), e);
+            exit(1);
+        }
+    };
+    let mut record = |entry: &Entry| {
+        let mut line = serde_json::to_vec(entry).expect("Unable to serialize journal entry");
+        line.push(b'\n');
+        journal
+            .write_all(&line)
+            .expect("Unable to write to the journal");
+    };
+
+    let (mut changed, mut failed) = (0, 0);
+    for step in &steps {
+        match step {
+            // The journal is JSON, which can't hold these paths exactly.
+            Ok(step) if step.path.to_str().is_none() => eprintln!(
+                "Skipping {}: Not valid Unicode, so it can't be recorded in the journal",
+                step.path.display()
+            ),
+            Ok(step) => match perform(step, action) {
+                Ok(entry) => {
+                    eprintln!("{}", describe(step, action));
+                    record(&entry);
+                    changed += 1;
+                }
+                Err((entry, e)) => {
+                    eprintln!("Unable to {}: {}", describe(step, action), e);
+                    if let Some(entry) = entry {
+                        record(&entry);
+                    }
+                    failed += 1;
+                }
+            },
+            Err(e) => eprintln!("Skipping {}", e),
+        }
+    }
+    eprintln!("{} files changed, {} failed.", changed, failed);
+    if changed > 0 || failed > 0 {
+        eprintln!(
+            "Journal written to {}, use `simagef undo` with it to reverse these changes.",
+            journal_path.display()
+        );
+    }
+}
+
+#[cfg(any(
+    target_os = "windows",
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+))]
+fn restore_from_trash(path: &Path) -> anyhow::Result<()> {
+    // The trash records the path with its directory canonicalized.
+    let original = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => std::fs::canonicalize(parent)?.join(name),
+        _ => path.to_path_buf(),
+    };
+    let item = trash::os_limited::list()?
+        .into_iter()
+        .filter(|item| item.original_path() == original)
+        .max_by_key(|item| item.time_deleted)
+        .ok_or_else(|| anyhow::anyhow!("Not found in the trash"))?;
+    trash::os_limited::restore_all([item])?;
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "windows",
+    all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        not(target_os = "android")
+    )
+)))]
+fn restore_from_trash(_path: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("Files can't be restored from the trash on this platform, restore it by hand")
+}
+
+/// Removes a link made by `--action` before its file is restored, as long as
+/// it still points at the image that was kept.
+fn remove_link(path: &Path, keeper: &Path, hard: bool) -> anyhow::Result<()> {
+    let unchanged = if hard {
+        same_file(path, keeper)?
+    } else {
+        std::fs::read_link(path)? == keeper
+    };
+    if !unchanged {
+        anyhow::bail!("Changed since it was linked to {}", keeper.display());
+    }
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+fn revert(entry: &Entry) -> anyhow::Result<()> {
+    match entry {
+        Entry::Delete { .. } => anyhow::bail!("Deleted, so it can't be restored"),
+        Entry::Trash { path } => restore_from_trash(path),
+        Entry::Move { path, to } => {
+            if path.exists() {
+                anyhow::bail!("Already exists");
+            }
+            Ok(move_file(to, path)?)
+        }
+        Entry::Hardlink { unlinked: true, .. } | Entry::Symlink { unlinked: true, .. } => {
+            anyhow::bail!("Replaced without going to the trash, so it can't be restored")
+        }
+        Entry::Hardlink { path, keeper, .. } => {
+            remove_link(path, keeper, true)?;
+            restore_from_trash(path)
+        }
+        Entry::Symlink { path, keeper, .. } => {
+            remove_link(path, keeper, false)?;
+            restore_from_trash(path)
+        }
+    }
+}
+
+fn read_journal(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+pub fn main_undo(journal: &Path) {
+    let entries = match read_journal(journal) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("{}: {}", journal.display(), e);
+            exit(1);
+        }
+    };
+
+    let mut failed = 0;
+    for entry in entries.iter().rev() {
+        let (Entry::Delete { path }
+        | Entry::Trash { path }
+        | Entry::Move { path, .. }
+        | Entry::Hardlink { path, .. }
+        | Entry::Symlink { path, .. }) = entry;
+        match revert(entry) {
+            Ok(()) => eprintln!("Restored {}", path.display()),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_journal_entry() {
+        let entry = Entry::Move {
+            path: "/photos/b.jpg".into(),
+            to: "/dupes/photos/b.jpg".into(),
+        };
+        let line = serde_json::to_string(&entry).unwrap();
+        assert_eq!(
+            line,
+            r#"{"action":"move","path":"/photos/b.jpg","to":"/dupes/photos/b.jpg"}"#
+        );
+        assert_eq!(serde_json::from_str::<Entry>(&line).unwrap(), entry);
+
+        // Links are only marked when their file didn't go to the trash.
+        let line = r#"{"action":"hardlink","path":"/photos/b.jpg","keeper":"/photos/a.jpg"}"#;
+        let entry = Entry::Hardlink {
+            path: "/photos/b.jpg".into(),
+            keeper: "/photos/a.jpg".into(),
+            unlinked: false,
+        };
+        assert_eq!(serde_json::from_str::<Entry>(line).unwrap(), entry);
+        assert_eq!(serde_json::to_string(&entry).unwrap(), line);
+        let entry = Entry::Symlink {
+            path: "/photos/b.jpg".into(),
+            keeper: "/photos/a.jpg".into(),
+            unlinked: true,
+        };
+        assert_eq!(
+            serde_json::to_string(&entry).unwrap(),
+            r#"{"action":"symlink","path":"/photos/b.jpg","keeper":"/photos/a.jpg","unlinked":true}"#
+        );
+    }
+
+    #[test]
+    fn test_plan_skips_keeper_under_another_path() {
+        let dir = std::env::temp_dir().join(format!("simagef-action-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (keeper, other) = (dir.join("a.png"), dir.join("b.png"));
+        std::fs::write(&keeper, b"a").unwrap();
+        std::fs::write(&other, b"b").unwrap();
+        // The keeper again, relative to the working directory.
+        let cwd = std::env::current_dir().unwrap();
+        let relative: PathBuf = cwd
+            .components()
+            .skip(1)
+            .map(|_| Component::ParentDir)
+            .chain(keeper.components().skip(1))
+            .collect();
+
+        let groups = vec![Group {
+            members: vec![&keeper, &relative, &other],
+            pairs: Vec::new(),
+            keeper: Some(&keeper),
+        }];
+        let plans = plan(&groups, &Action::Delete);
+        let steps: Vec<Result<&Path, &String>> = plans[0]
+            .iter()
+            .map(|step| step.as_ref().map(|step| step.path))
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                Err(&format!(
+                    "{}: The same file as {}, which is kept",
+                    relative.display(),
+                    keeper.display()
+                )),
+                Ok(other.as_path()),
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_move_destination() {
+        assert_eq!(
+            move_destination(Path::new("/dupes"), Path::new("/photos/2024/b.jpg")).unwrap(),
+            PathBuf::from("/dupes/photos/2024/b.jpg")
+        );
+    }
+}
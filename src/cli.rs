@@ -1,6 +1,7 @@
-use std::fmt::Display;
+use std::{fmt::Display, path::PathBuf, str::FromStr};
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+use image::ImageFormat;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Fmt {
@@ -13,6 +14,21 @@ pub enum Fmt {
     /// Filenames are separated by NUL characters. Groups are separated by
     /// two consecutive NUL characters.
     Null,
+    /// A JSON array of pairs or groups, with the score of each pair and the
+    /// dimensions and file size of each image.
+    Json,
+    /// Same as JSON, but with one pair or group per line instead of an array.
+    Jsonl,
+    /// One row per pair with the scores and image details, along with the
+    /// group each pair belongs to.
+    Csv,
+    /// Same as CSV, but separated by tabs.
+    Tsv,
+    /// A Graphviz graph with a node for each image and an edge for each pair,
+    /// weighted by its score.
+    Dot,
+    /// The same graph as GraphML, for Gephi and other graph tools.
+    Graphml,
 }
 
 impl Display for Fmt {
@@ -21,6 +37,12 @@ impl Display for Fmt {
             Fmt::Regular => f.write_str("regular"),
             Fmt::Quote => f.write_str("quote"),
             Fmt::Null => f.write_str("null"),
+            Fmt::Json => f.write_str("json"),
+            Fmt::Jsonl => f.write_str("jsonl"),
+            Fmt::Csv => f.write_str("csv"),
+            Fmt::Tsv => f.write_str("tsv"),
+            Fmt::Dot => f.write_str("dot"),
+            Fmt::Graphml => f.write_str("graphml"),
         }
     }
 }
@@ -31,34 +53,414 @@ impl From<&str> for Fmt {
             "regular" => Self::Regular,
             "quote" => Self::Quote,
             "null" => Self::Null,
+            "json" => Self::Json,
+            "jsonl" => Self::Jsonl,
+            "csv" => Self::Csv,
+            "tsv" => Self::Tsv,
+            "dot" => Self::Dot,
+            "graphml" => Self::Graphml,
             _ => panic!("Unknown option for --format"),
         }
     }
 }
 
+/// How matched pairs are turned into groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cluster {
+    /// Images are grouped with everything they are connected to through a
+    /// chain of matches, even if the ends of the chain look nothing alike.
+    Components,
+    /// Every member of a group matches every other member.
+    CompleteLinkage,
+    /// Each group is a representative along with the images that match it,
+    /// starting with the images that have the most matches.
+    Star,
+    /// The members of a group match each other on average. Unlike complete
+    /// linkage, this tolerates the odd pair that wasn't compared.
+    AverageLinkage,
+}
+
+impl Display for Cluster {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cluster::Components => f.write_str("components"),
+            Cluster::CompleteLinkage => f.write_str("complete-linkage"),
+            Cluster::Star => f.write_str("star"),
+            Cluster::AverageLinkage => f.write_str("average-linkage"),
+        }
+    }
+}
+
+impl From<&str> for Cluster {
+    fn from(value: &str) -> Self {
+        match value {
+            "components" => Self::Components,
+            "complete-linkage" => Self::CompleteLinkage,
+            "star" => Self::Star,
+            "average-linkage" => Self::AverageLinkage,
+            _ => panic!("Unknown option for --cluster"),
+        }
+    }
+}
+
+/// When the content of files is hashed to check the signatures stored in the
+/// database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentHash {
+    /// Never. Stored signatures are only used for the same path with the same
+    /// size and modification time.
+    Never,
+    /// When a file isn't stored under its path with the same size and
+    /// modification time, so moved and touched files reuse their signature.
+    Fallback,
+    /// Always, so files changed without changing their size or modification
+    /// time are noticed too.
+    Always,
+}
+
+impl Display for ContentHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContentHash::Never => f.write_str("never"),
+            ContentHash::Fallback => f.write_str("fallback"),
+            ContentHash::Always => f.write_str("always"),
+        }
+    }
+}
+
+impl From<&str> for ContentHash {
+    fn from(value: &str) -> Self {
+        match value {
+            "never" => Self::Never,
+            "fallback" => Self::Fallback,
+            "always" => Self::Always,
+            _ => panic!("Unknown option for --content-hash"),
+        }
+    }
+}
+
+/// A rule for picking the image to keep in each group.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Keep {
+    /// The most pixels.
+    Resolution,
+    /// The largest file.
+    Largest,
+    /// The earliest modification time.
+    Oldest,
+    /// The latest modification time.
+    Newest,
+    /// The earliest format in the list. Formats that aren't listed come last.
+    Format(Vec<ImageFormat>),
+    /// The shortest path.
+    ShortestPath,
+    /// Inside the directory.
+    Prefix(PathBuf),
+}
+
+impl FromStr for Keep {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(formats) = value.strip_prefix("format:") {
+            return formats
+                .split('>')
+                .map(|extension| {
+                    ImageFormat::from_extension(extension)
+                        .ok_or_else(|| format!("Unknown image format '{}'", extension))
+                })
+                .collect::<Result<_, _>>()
+                .map(Self::Format);
+        }
+        if let Some(prefix) = value.strip_prefix("prefix:") {
+            return Ok(Self::Prefix(PathBuf::from(prefix)));
+        }
+        match value {
+            "resolution" => Ok(Self::Resolution),
+            "largest" => Ok(Self::Largest),
+            "oldest" => Ok(Self::Oldest),
+            "newest" => Ok(Self::Newest),
+            "shortest-path" => Ok(Self::ShortestPath),
+            _ => Err(format!("Unknown rule '{}'", value)),
+        }
+    }
+}
+
+/// What to do with the images that aren't kept in a group.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Delete for good, which can't be undone.
+    Delete,
+    /// Move to the trash of the desktop environment.
+    Trash,
+    /// Move into the directory, keeping the rest of the path below it.
+    Move(PathBuf),
+    /// Replace with a hard link to the image kept.
+    Hardlink,
+    /// Replace with a symbolic link to the image kept.
+    Symlink,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(dir) = value.strip_prefix("move:") {
+            return Ok(Self::Move(PathBuf::from(dir)));
+        }
+        match value {
+            "delete" => Ok(Self::Delete),
+            "trash" => Ok(Self::Trash),
+            "hardlink" => Ok(Self::Hardlink),
+            "symlink" => Ok(Self::Symlink),
+            _ => Err(format!("Unknown action '{}'", value)),
+        }
+    }
+}
+
+/// The commands written by --emit-script.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Script {
+    Rm,
+    /// Move into the directory, keeping the rest of the path below it.
+    Mv(PathBuf),
+    /// Replace with a hard link to the image kept.
+    Ln,
+}
+
+impl FromStr for Script {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(dir) = value.strip_prefix("mv:") {
+            return Ok(Self::Mv(PathBuf::from(dir)));
+        }
+        match value {
+            "rm" => Ok(Self::Rm),
+            "ln" => Ok(Self::Ln),
+            "mv" => Err("mv needs a directory to move files to, as in mv:<dir>".to_string()),
+            _ => Err(format!("Unknown script '{}'", value)),
+        }
+    }
+}
+
+/// How `simagef review` shows the selected image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preview {
+    /// No preview, only the details of the image.
+    None,
+    /// The kitty graphics protocol, also supported by WezTerm and Ghostty.
+    Kitty,
+    /// Sixel graphics, supported by foot, xterm and others.
+    Sixel,
+}
+
+impl Display for Preview {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Preview::None => f.write_str("none"),
+            Preview::Kitty => f.write_str("kitty"),
+            Preview::Sixel => f.write_str("sixel"),
+        }
+    }
+}
+
+impl From<&str> for Preview {
+    fn from(value: &str) -> Self {
+        match value {
+            "none" => Self::None,
+            "kitty" => Self::Kitty,
+            "sixel" => Self::Sixel,
+            _ => panic!("Unknown option for --preview"),
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct QueryArgs {
+    /// The images to look up in the database.
+    #[arg(required = true)]
+    pub images: Vec<PathBuf>,
+    /// Only match images whose path starts with this directory.
+    #[arg(long)]
+    pub prefix: Option<PathBuf>,
+    /// The maximum number of matches to print for each image.
+    #[arg(short('n'), long, default_value_t = 10)]
+    pub limit: usize,
+}
+
+#[derive(Args)]
+pub struct WatchArgs {
+    /// The directories to watch, including their subdirectories.
+    #[arg(required = true)]
+    pub dirs: Vec<PathBuf>,
+    /// How long a file has to go unchanged before it is compared, in
+    /// milliseconds, so files that are still being written are left alone.
+    #[arg(long, default_value_t = 2000)]
+    pub settle: u64,
+}
+
+#[derive(Args)]
+pub struct ReviewArgs {
+    /// The files to compare. Directories are searched for files recursively.
+    /// If one of these is a dash '-' the program will also read filenames
+    /// from stdin.
+    pub files: Vec<PathBuf>,
+    /// What to do with the images marked for deletion - delete, trash,
+    /// move:<dir>, hardlink, symlink.
+    #[arg(long, default_value = "trash")]
+    pub action: Action,
+    /// Where to record the changes made, so they can be reversed with
+    /// `simagef undo`. Defaults to a new file in the data directory.
+    #[arg(long)]
+    pub journal: Option<PathBuf>,
+    /// Show the selected image in the terminal - none, kitty, sixel.
+    #[arg(long, default_value_t = Preview::None)]
+    pub preview: Preview,
+}
+
+#[derive(Args)]
+pub struct IgnoreArgs {
+    /// The images that aren't duplicates of each other.
+    #[arg(required = true, num_args = 2..)]
+    pub images: Vec<PathBuf>,
+    /// Report matches between the images again.
+    #[arg(long)]
+    pub remove: bool,
+}
+
+#[derive(Args)]
+pub struct UndoArgs {
+    /// The journal written by --action --apply.
+    pub journal: PathBuf,
+}
+
+#[derive(Args)]
+pub struct DbArgs {
+    #[command(subcommand)]
+    pub command: DbCommand,
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Show how many signatures are stored, the size of the database file, the
+    /// oldest file and how many signatures each directory has.
+    Stats,
+    /// Remove the signatures of files that no longer exist.
+    Prune(PruneArgs),
+    /// Rebuild the database file to reclaim the space left by removed
+    /// signatures. Stored indexes are dropped and rebuilt when next used.
+    Vacuum,
+    /// Check the database file for corruption and signatures of the wrong
+    /// length.
+    Check,
+}
+
+#[derive(Args)]
+pub struct PruneArgs {
+    /// Remove every signature under this directory instead, whether or not
+    /// the files still exist.
+    #[arg(long)]
+    pub prefix: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Find the closest matches for images among the signatures already in the
+    /// database, without rescanning the files they came from.
+    Query(QueryArgs),
+    /// Watch directories and compare each new or modified image against the
+    /// database as it lands. Every image seen is added to the database.
+    Watch(WatchArgs),
+    /// Step through the groups in the terminal, deciding which images to keep
+    /// and which to delete, then make the changes all at once. Options for the
+    /// search go before `review`.
+    Review(ReviewArgs),
+    /// Mark images as not being duplicates of each other, so matches between
+    /// them aren't reported again. Images are recognized by their content, so
+    /// this carries over when they are renamed or moved.
+    Ignore(IgnoreArgs),
+    /// Reverse the changes recorded in a journal by --action, newest first.
+    /// Deleted files can't be brought back.
+    Undo(UndoArgs),
+    /// Inspect and clean up the database.
+    Db(DbArgs),
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     /// Compare images by shrinking them to identical sizes and comparing the pixel values, instead of signatures.
     #[arg(short('m'), long, default_value_t = false)]
     pub pixels: bool,
     /// The amount of similarity as a percentage to be considered similar.
-    #[arg(short, long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    #[arg(short, long, global = true, value_parser = clap::value_parser!(u8).range(0..=100))]
     pub threshold: Option<u8>,
     /// The program to launch when the comparisons are finished.
     /// The program will be launched for each pair or grouping, one after another.
-    #[arg(short('e'), long)]
+    #[arg(short('e'), long, global = true)]
     #[cfg(not(feature = "no-exec"))]
     pub exec: Option<String>,
     /// If set, will only present the matched images in pairs rather than groups.
     #[arg(short('p'), long, default_value_t = false)]
     pub pairs: bool,
+    /// Only look for files that are exact copies of each other, without
+    /// decoding any images. Exact copies are also found without this, before
+    /// comparing the images that are left.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["reference", "pixels"])]
+    pub exact: bool,
+    /// Hash the decoded pixels of each image, and mark pairs of images with
+    /// the same pixels as pixel-identical. Images stored in the database
+    /// before their pixels were hashed are decoded again once.
+    #[arg(long, default_value_t = false, conflicts_with = "pixels")]
+    pub pixel_identical: bool,
+    /// How to form groups from the matched pairs - components,
+    /// complete-linkage, star, average-linkage.
+    #[arg(long, default_value_t = Cluster::Components)]
+    pub cluster: Cluster,
+    /// Rules for picking the image to keep in each group, separated by commas
+    /// and tried in order until one prefers an image - resolution, largest,
+    /// oldest, newest, format:png>webp>jpg, shortest-path, prefix:<dir>. The
+    /// image kept is listed first.
+    #[arg(long, value_delimiter = ',')]
+    pub keep: Vec<Keep>,
+    /// What to do with the images that aren't kept in each group - delete,
+    /// trash, move:<dir>, hardlink, symlink. Only lists the changes unless
+    /// --apply is given.
+    #[arg(long, requires = "keep", conflicts_with = "pairs")]
+    pub action: Option<Action>,
+    /// Make the changes listed by --action.
+    #[arg(long, requires = "action")]
+    pub apply: bool,
+    /// Only list the changes --action would make. This is the default.
+    #[arg(long, requires = "action", conflicts_with = "apply")]
+    pub dry_run: bool,
+    /// Where to record the changes made by --action, so they can be reversed
+    /// with `simagef undo`. Defaults to a new file in the data directory.
+    #[arg(long, requires = "action")]
+    pub journal: Option<PathBuf>,
+    /// Print a shell script to deal with the images that aren't kept in each
+    /// group instead of the groups, for review before running it - rm,
+    /// mv:<dir>, ln.
+    #[arg(
+        long,
+        requires = "keep",
+        conflicts_with_all = ["pairs", "action", "format"]
+    )]
+    pub emit_script: Option<Script>,
     /// By default we use a database to store signatures, speeding up subsequent runs.
     #[arg(short('d'), long, default_value_t = false)]
     pub no_database: bool,
     /// The path for the database file. Will be created if it doesn't exist.
-    #[arg(long)]
-    pub database_file: Option<String>,
+    #[arg(long, global = true)]
+    pub database_file: Option<PathBuf>,
+    /// When to hash the content of files to find their stored signatures -
+    /// never, fallback, always. With fallback, files that were moved, renamed
+    /// or touched are recognized by their content.
+    #[arg(long, global = true, default_value_t = ContentHash::Fallback)]
+    pub content_hash: ContentHash,
     /// Print database file location and exit.
     #[arg(long)]
     pub print_database_location: bool,
@@ -68,10 +470,61 @@ pub struct Cli {
     /// The height to resize the images to before comparing in pixel mode.
     #[arg(long, default_value_t = 160)]
     pub height: u32,
-    /// The files to compare. If one of these is a dash '-' the program will
-    /// also read filenames from stdin.
-    pub files: Vec<String>,
-    /// Format to use for printing the filenames - regular, quote, null.
-    #[arg(long, default_value_t = Fmt::Regular)]
+    /// The files to compare. Directories are searched for files recursively.
+    /// If one of these is a dash '-' the program will also read filenames
+    /// from stdin.
+    pub files: Vec<PathBuf>,
+    /// Compare the query images only against these reference images and
+    /// directories, without reporting matches within either set. A dash reads
+    /// filenames from stdin.
+    #[arg(long, num_args = 1.., requires = "query", conflicts_with = "files")]
+    pub reference: Vec<PathBuf>,
+    /// The images and directories to look up in the reference set given with
+    /// --reference. A dash reads filenames from stdin.
+    #[arg(long, num_args = 1.., requires = "reference", conflicts_with = "files")]
+    pub query: Vec<PathBuf>,
+    /// Filenames read from stdin are separated by NUL characters instead of
+    /// newlines, as printed by `find -print0` or `fd -0`.
+    #[arg(short('0'), long, default_value_t = false)]
+    pub null_input: bool,
+    /// The maximum depth to descend to when searching directories. A depth of
+    /// 1 only includes the files directly inside the directory.
+    #[arg(long)]
+    pub max_depth: Option<usize>,
+    /// Follow symbolic links when searching directories.
+    #[arg(short('L'), long, default_value_t = false)]
+    pub follow_symlinks: bool,
+    /// Don't cross filesystem boundaries when searching directories.
+    #[arg(long, default_value_t = false)]
+    pub one_file_system: bool,
+    /// Include hidden files and directories when searching directories.
+    #[arg(short('H'), long, default_value_t = false)]
+    pub hidden: bool,
+    /// Only compare files matching one of these glob patterns. Patterns are
+    /// matched against both the full path and the file name.
+    #[arg(long, global = true)]
+    pub include: Vec<String>,
+    /// Skip files and directories matching one of these glob patterns.
+    #[arg(long, global = true)]
+    pub exclude: Vec<String>,
+    /// Only compare files with one of these extensions, separated by commas.
+    #[arg(long, global = true, value_delimiter = ',')]
+    pub ext: Vec<String>,
+    /// Additional ignore file in gitignore syntax to apply to the files
+    /// compared. Files named .simagefignore are always applied to the
+    /// directory they are in.
+    #[arg(long, global = true)]
+    pub ignore_file: Vec<PathBuf>,
+    /// Format to use for printing the filenames - regular, quote, null, json,
+    /// jsonl, csv, tsv, dot, graphml.
+    #[arg(long, global = true, default_value_t = Fmt::Regular)]
     pub format: Fmt,
+    /// Also write the groups to this file as an HTML page, with a thumbnail
+    /// and details of each image. The page has no outside dependencies.
+    #[arg(long)]
+    pub report: Option<PathBuf>,
+    /// Also write an image of each group to this directory, with its members
+    /// side by side and captioned with their names, dimensions and scores.
+    #[arg(long)]
+    pub contact_sheet: Option<PathBuf>,
 }
@@ -0,0 +1,381 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use crate::cli::Cluster;
+
+/// A matched pair of images by index, along with its score.
+pub type Edge = (usize, usize, f64);
+
+/// Groups the images of the matched pairs. Images that end up without a match
+/// in their group are left out. `threshold` is the score pairs had to exceed
+/// to be matched.
+pub fn cluster(method: Cluster, edges: &[Edge], threshold: f64) -> Vec<Vec<usize>> {
+    match method {
+        Cluster::Components => components(edges),
+        Cluster::CompleteLinkage => linkage(edges, threshold, Linkage::Complete),
+        Cluster::Star => star(edges),
+        Cluster::AverageLinkage => linkage(edges, threshold, Linkage::Average),
+    }
+}
+
+fn adjacency(edges: &[Edge]) -> HashMap<usize, Vec<(usize, f64)>> {
+    let mut graph: HashMap<usize, Vec<(usize, f64)>> = HashMap::new();
+    for (node1, node2, score) in edges {
+        graph.entry(*node1).or_default().push((*node2, *score));
+        graph.entry(*node2).or_default().push((*node1, *score));
+    }
+    graph
+}
+
+fn components(edges: &[Edge]) -> Vec<Vec<usize>> {
+    let graph = adjacency(edges);
+
+    let mut visited: HashSet<usize> = HashSet::new();
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+
+    // Perform DFS to find connected components
+    fn dfs(
+        node: usize,
+        graph: &HashMap<usize, Vec<(usize, f64)>>,
+        visited: &mut HashSet<usize>,
+        mut group: Vec<usize>,
+    ) -> Vec<usize> {
+        visited.insert(node);
+        group.push(node);
+        if let Some(neighbors) = graph.get(&node) {
+            for (neighbor, _) in neighbors.iter() {
+                if !visited.contains(neighbor) {
+                    group = dfs(*neighbor, graph, visited, group);
+                }
+            }
+        }
+        group
+    }
+
+    for node in graph.keys() {
+        if !visited.contains(node) {
+            let mut group: Vec<usize> = Vec::new();
+            group = dfs(*node, &graph, &mut visited, group);
+            groups.push(group);
+        }
+    }
+
+    groups
+}
+
+/// Picks the image with the most matches as the representative of a group,
+/// along with all of its matches that aren't in a group yet, and repeats. The
+/// representative comes first in its group.
+fn star(edges: &[Edge]) -> Vec<Vec<usize>> {
+    let graph = adjacency(edges);
+
+    // Ties go to the image with the higher total score, then the lower index.
+    let mut centers: Vec<(usize, usize, f64)> = graph
+        .iter()
+        .map(|(node, neighbors)| {
+            let total = neighbors.iter().map(|(_, score)| score).sum();
+            (*node, neighbors.len(), total)
+        })
+        .collect();
+    centers.sort_by(|a, b| {
+        b.1.cmp(&a.1)
+            .then_with(|| b.2.total_cmp(&a.2))
+            .then_with(|| a.0.cmp(&b.0))
+    });
+
+    let mut assigned: HashSet<usize> = HashSet::new();
+    let mut groups = Vec::new();
+    for (center, _, _) in centers {
+        if assigned.contains(&center) {
+            continue;
+        }
+        let mut group = vec![center];
+        group.extend(
+            graph[&center]
+                .iter()
+                .map(|(neighbor, _)| *neighbor)
+                .filter(|neighbor| !assigned.contains(neighbor)),
+        );
+        if group.len() > 1 {
+            assigned.extend(group.iter().copied());
+            groups.push(group);
+        }
+    }
+
+    groups
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Linkage {
+    Complete,
+    Average,
+}
+
+/// The scores between the members of two clusters.
+#[derive(Clone, Copy)]
+struct Link {
+    sum: f64,
+    min: f64,
+    /// How many pairs between the two clusters were matched.
+    count: usize,
+}
+
+impl Link {
+    fn merge(self, other: Link) -> Link {
+        Link {
+            sum: self.sum + other.sum,
+            min: self.min.min(other.min),
+            count: self.count + other.count,
+        }
+    }
+
+    /// How similar two clusters of the given sizes are, or `None` if they
+    /// shouldn't be merged. Average linkage averages the pairs that matched,
+    /// which have to be more than half of them, so the odd missed pair is
+    /// tolerated but a chain is still broken up.
+    fn similarity(
+        &self,
+        linkage: Linkage,
+        size1: usize,
+        size2: usize,
+        threshold: f64,
+    ) -> Option<f64> {
+        let pairs = size1 * size2;
+        match linkage {
+            Linkage::Complete => (self.count == pairs).then_some(self.min),
+            Linkage::Average => (self.count * 2 > pairs)
+                .then(|| self.sum / self.count as f64)
+                .filter(|average| *average > threshold),
+        }
+    }
+}
+
+/// A merge that was possible when it was queued. It's stale if either cluster
+/// has changed since.
+struct Candidate {
+    similarity: f64,
+    clusters: (usize, usize),
+    versions: (usize, usize),
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.similarity
+            .total_cmp(&other.similarity)
+            .then_with(|| other.clusters.cmp(&self.clusters))
+    }
+}
+
+/// The state of agglomerative clustering. Each cluster starts out as a single
+/// image, and is emptied when it is merged into another.
+struct Clusters {
+    linkage: Linkage,
+    threshold: f64,
+    members: Vec<Vec<usize>>,
+    /// Bumped whenever a cluster changes, to tell stale candidates apart.
+    versions: Vec<usize>,
+    links: Vec<HashMap<usize, Link>>,
+}
+
+impl Clusters {
+    fn candidate(&self, cluster1: usize, cluster2: usize) -> Option<Candidate> {
+        let (cluster1, cluster2) = (cluster1.min(cluster2), cluster1.max(cluster2));
+        let link = self.links[cluster1].get(&cluster2)?;
+        let similarity = link.similarity(
+            self.linkage,
+            self.members[cluster1].len(),
+            self.members[cluster2].len(),
+            self.threshold,
+        )?;
+        Some(Candidate {
+            similarity,
+            clusters: (cluster1, cluster2),
+            versions: (self.versions[cluster1], self.versions[cluster2]),
+        })
+    }
+
+    fn is_current(&self, candidate: &Candidate) -> bool {
+        let (cluster1, cluster2) = candidate.clusters;
+        candidate.versions == (self.versions[cluster1], self.versions[cluster2])
+    }
+
+    /// Moves the members of `absorb` into `keep`, combining their links to
+    /// other clusters.
+    fn merge(&mut self, keep: usize, absorb: usize) {
+        let absorbed = std::mem::take(&mut self.members[absorb]);
+        self.members[keep].extend(absorbed);
+        self.versions[keep] += 1;
+        self.versions[absorb] += 1;
+
+        let absorbed_links = std::mem::take(&mut self.links[absorb]);
+        self.links[keep].remove(&absorb);
+        for (other, link) in absorbed_links {
+            if other == keep {
+                continue;
+            }
+            self.links[other].remove(&absorb);
+            let merged = match self.links[keep].get(&other) {
+                Some(existing) => existing.merge(link),
+                None => link,
+            };
+            self.links[keep].insert(other, merged);
+            self.links[other].insert(keep, merged);
+        }
+    }
+}
+
+/// Agglomerative clustering: starting from single images, keeps merging the
+/// two most similar clusters until no two clusters are similar enough.
+fn linkage(edges: &[Edge], threshold: f64, linkage: Linkage) -> Vec<Vec<usize>> {
+    let mut cluster_of: HashMap<usize, usize> = HashMap::new();
+    let mut members: Vec<Vec<usize>> = Vec::new();
+    for (node1, node2, _) in edges {
+        for node in [node1, node2] {
+            cluster_of.entry(*node).or_insert_with(|| {
+                members.push(vec![*node]);
+                members.len() - 1
+            });
+        }
+    }
+
+    let mut links: Vec<HashMap<usize, Link>> = vec![HashMap::new(); members.len()];
+    for (node1, node2, score) in edges {
+        let (cluster1, cluster2) = (cluster_of[node1], cluster_of[node2]);
+        if cluster1 == cluster2 {
+            continue;
+        }
+        let link = Link {
+            sum: *score,
+            min: *score,
+            count: 1,
+        };
+        links[cluster1].insert(cluster2, link);
+        links[cluster2].insert(cluster1, link);
+    }
+
+    let mut clusters = Clusters {
+        linkage,
+        threshold,
+        versions: vec![0; members.len()],
+        members,
+        links,
+    };
+
+    let mut queue = BinaryHeap::new();
+    for (cluster1, cluster_links) in clusters.links.iter().enumerate() {
+        for cluster2 in cluster_links.keys() {
+            if cluster1 < *cluster2 {
+                queue.extend(clusters.candidate(cluster1, *cluster2));
+            }
+        }
+    }
+
+    while let Some(candidate) = queue.pop() {
+        if !clusters.is_current(&candidate) {
+            continue;
+        }
+        let (keep, absorb) = candidate.clusters;
+        clusters.merge(keep, absorb);
+        for other in clusters.links[keep].keys() {
+            queue.extend(clusters.candidate(keep, *other));
+        }
+    }
+
+    clusters
+        .members
+        .into_iter()
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn sorted(mut groups: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
+        for group in &mut groups {
+            group.sort();
+        }
+        groups.sort();
+        groups
+    }
+
+    /// 0 and 2 only match through 1.
+    const CHAIN: [Edge; 2] = [(0, 1, 0.95), (1, 2, 0.93)];
+
+    #[test]
+    fn test_chain() {
+        assert_eq!(
+            sorted(cluster(Cluster::Components, &CHAIN, 0.9)),
+            vec![vec![0, 1, 2]]
+        );
+        assert_eq!(
+            sorted(cluster(Cluster::CompleteLinkage, &CHAIN, 0.9)),
+            vec![vec![0, 1]]
+        );
+        assert_eq!(
+            sorted(cluster(Cluster::AverageLinkage, &CHAIN, 0.9)),
+            vec![vec![0, 1]]
+        );
+        assert_eq!(cluster(Cluster::Star, &CHAIN, 0.9), vec![vec![1, 0, 2]]);
+    }
+
+    #[test]
+    fn test_clique() {
+        let edges = [(0, 1, 0.95), (1, 2, 0.93), (0, 2, 0.91), (3, 4, 0.99)];
+        for method in [
+            Cluster::Components,
+            Cluster::CompleteLinkage,
+            Cluster::Star,
+            Cluster::AverageLinkage,
+        ] {
+            assert_eq!(
+                sorted(cluster(method, &edges, 0.9)),
+                vec![vec![0, 1, 2], vec![3, 4]]
+            );
+        }
+    }
+
+    #[test]
+    fn test_missing_pair() {
+        // Everything matches except 0 and 3.
+        let edges = [
+            (0, 1, 0.99),
+            (0, 2, 0.99),
+            (1, 2, 0.99),
+            (1, 3, 0.99),
+            (2, 3, 0.99),
+        ];
+        assert_eq!(
+            sorted(cluster(Cluster::AverageLinkage, &edges, 0.9)),
+            vec![vec![0, 1, 2, 3]]
+        );
+        let complete = sorted(cluster(Cluster::CompleteLinkage, &edges, 0.9));
+        assert_eq!(complete.len(), 1);
+        assert_eq!(complete[0].len(), 3);
+    }
+
+    #[test]
+    fn test_star() {
+        // 1 has the most matches, so 0 and 2 join it and 3 is left alone.
+        let edges = [(0, 1, 0.95), (1, 2, 0.95), (2, 3, 0.95)];
+        assert_eq!(cluster(Cluster::Star, &edges, 0.9), vec![vec![1, 0, 2]]);
+    }
+}
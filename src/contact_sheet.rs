@@ -0,0 +1,248 @@
+use std::{collections::HashMap, convert::Infallible, path::Path, thread};
+
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_7X13, MonoTextStyle},
+    pixelcolor::Rgb888,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+use image::{
+    codecs::jpeg::JpegEncoder,
+    imageops::{self, FilterType},
+    DynamicImage, Rgb, RgbImage,
+};
+
+use crate::{
+    formatting::{Group, Match},
+    open_image::{open_image_path, IBoft},
+    sniff::sniff_format,
+};
+
+/// The largest width or height of an image on the sheet, in pixels.
+const TILE_SIZE: u32 = 256;
+const COLUMNS: u32 = 4;
+const PADDING: u32 = 8;
+const LINE_HEIGHT: u32 = 15;
+const CAPTION_LINES: u32 = 3;
+/// How many characters of the font fit in the width of a tile.
+const CAPTION_CHARS: usize = (TILE_SIZE / 7) as usize;
+
+const BACKGROUND: Rgb<u8> = Rgb([255, 255, 255]);
+const PLACEHOLDER: Rgb<u8> = Rgb([221, 221, 221]);
+const KEEPER: Rgb888 = Rgb888::new(42, 122, 58);
+
+/// Lets embedded-graphics draw captions onto an image buffer.
+struct Canvas(RgbImage);
+
+impl OriginDimensions for Canvas {
+    fn size(&self) -> Size {
+        Size::new(self.0.width(), self.0.height())
+    }
+}
+
+impl DrawTarget for Canvas {
+    type Color = Rgb888;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let (Ok(x), Ok(y)) = (u32::try_from(point.x), u32::try_from(point.y)) {
+                if x < self.0.width() && y < self.0.height() {
+                    self.0
+                        .put_pixel(x, y, Rgb([color.r(), color.g(), color.b()]));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The size of an image scaled down to fit in a tile, keeping its aspect
+/// ratio. Images smaller than a tile are left as they are.
+fn fit_in_tile(width: u32, height: u32) -> (u32, u32) {
+    if width <= TILE_SIZE && height <= TILE_SIZE {
+        return (width, height);
+    }
+    if width >= height {
+        let height = (height as u64 * TILE_SIZE as u64 / width as u64).max(1);
+        (TILE_SIZE, height as u32)
+    } else {
+        let width = (width as u64 * TILE_SIZE as u64 / height as u64).max(1);
+        (width as u32, TILE_SIZE)
+    }
+}
+
+/// Shortens a caption to fit under a tile, keeping the end of it since that's
+/// where file names differ the most.
+fn fit_caption(text: &str) -> String {
+    let count = text.chars().count();
+    if count <= CAPTION_CHARS {
+        text.to_string()
+    } else {
+        let tail: String = text.chars().skip(count - CAPTION_CHARS + 3).collect();
+        format!("...{}", tail)
+    }
+}
+
+fn open_thumbnail(path: &Path) -> anyhow::Result<(IBoft, u32, u32)> {
+    let format = sniff_format(path)?.ok_or(anyhow::anyhow!("Not an image"))?;
+    let image = open_image_path(path, format)?;
+    let (width, height) = image.dimensions();
+    let (tile_width, tile_height) = fit_in_tile(width, height);
+    let thumbnail = imageops::resize(&image, tile_width, tile_height, FilterType::Triangle);
+    Ok((thumbnail, width, height))
+}
+
+/// Tiles the members of a group, each captioned with its file name, its
+/// dimensions and its best score against another member, or whether it is an
+/// exact copy of one or has the same pixels. The image kept in the group is
+/// framed.
+fn render_sheet(group: &Group) -> RgbImage {
+    let mut best_scores: HashMap<&Path, f64> = HashMap::new();
+    let mut identical: HashMap<&Path, Match> = HashMap::new();
+    for (a, b, score, kind) in &group.pairs {
+        for path in [a, b] {
+            let best = best_scores.entry(path).or_insert(*score);
+            *best = best.max(*score);
+            match kind {
+                Match::Exact => {
+                    identical.insert(path, Match::Exact);
+                }
+                Match::Pixel => {
+                    identical.entry(path).or_insert(Match::Pixel);
+                }
+                Match::Perceptual => (),
+            }
+        }
+    }
+
+    let count = group.members.len() as u32;
+    let columns = count.clamp(1, COLUMNS);
+    let rows = count.div_ceil(COLUMNS).max(1);
+    let cell_width = TILE_SIZE + PADDING;
+    let cell_height = TILE_SIZE + CAPTION_LINES * LINE_HEIGHT + 2 * PADDING;
+    let mut canvas = Canvas(RgbImage::from_pixel(
+        columns * cell_width + PADDING,
+        rows * cell_height + PADDING,
+        BACKGROUND,
+    ));
+    let style = MonoTextStyle::new(&FONT_7X13, Rgb888::BLACK);
+
+    for (i, path) in group.members.iter().enumerate() {
+        let x = PADDING + (i as u32 % COLUMNS) * cell_width;
+        let y = PADDING + (i as u32 / COLUMNS) * cell_height;
+
+        let dimensions = match open_thumbnail(path) {
+            Ok((thumbnail, width, height)) => {
+                let tile = DynamicImage::ImageRgba8(thumbnail).to_rgb8();
+                let offset_x = (TILE_SIZE - tile.width()) / 2;
+                let offset_y = (TILE_SIZE - tile.height()) / 2;
+                imageops::overlay(
+                    &mut canvas.0,
+                    &tile,
+                    (x + offset_x).into(),
+                    (y + offset_y).into(),
+                );
+                format!("{} × {}", width, height)
+            }
+            Err(e) => {
+                let placeholder = RgbImage::from_pixel(TILE_SIZE, TILE_SIZE, PLACEHOLDER);
+                imageops::overlay(&mut canvas.0, &placeholder, x.into(), y.into());
+                e.to_string()
+            }
+        };
+
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut score = match identical.get(path) {
+            Some(Match::Exact) => "exact copy".to_string(),
+            Some(_) => "identical pixels".to_string(),
+            None => best_scores
+                .get(path)
+                .map(|score| format!("score {:.4}", score))
+                .unwrap_or_default(),
+        };
+        if group.keeper == Some(*path) {
+            score.push_str(", keep");
+            Rectangle::new(
+                Point::new(x as i32 - 3, y as i32 - 3),
+                Size::new(TILE_SIZE + 6, TILE_SIZE + 6),
+            )
+            .into_styled(PrimitiveStyle::with_stroke(KEEPER, 3))
+            .draw(&mut canvas)
+            .ok();
+        }
+        for (line, text) in [name, dimensions, score].iter().enumerate() {
+            let position = Point::new(
+                x as i32,
+                (y + TILE_SIZE + PADDING / 2 + line as u32 * LINE_HEIGHT) as i32,
+            );
+            Text::with_baseline(&fit_caption(text), position, style, Baseline::Top)
+                .draw(&mut canvas)
+                .ok();
+        }
+    }
+
+    canvas.0
+}
+
+fn write_sheet(path: &Path, group: &Group) -> anyhow::Result<()> {
+    let sheet = render_sheet(group);
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    JpegEncoder::new_with_quality(file, 90).encode_image(&sheet)?;
+    Ok(())
+}
+
+/// Writes a JPEG for each group to `dir`, named after the group's number in
+/// the output. Groups are rendered in parallel, since decoding the images
+/// takes most of the time.
+pub fn write_contact_sheets(dir: &Path, groups: &[Group]) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    let numbered: Vec<(usize, &Group)> = groups.iter().enumerate().collect();
+    let chunk_size = numbered.len().div_ceil(num_cpus::get()).max(1);
+    thread::scope(|scope| {
+        for chunk in numbered.chunks(chunk_size) {
+            scope.spawn(move || {
+                for (i, group) in chunk {
+                    let path = dir.join(format!("group-{:04}.jpg", i + 1));
+                    if let Err(e) = write_sheet(&path, group) {
+                        eprintln!("{}: {}", path.display(), e);
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_fit_in_tile() {
+        assert_eq!(fit_in_tile(100, 50), (100, 50));
+        assert_eq!(fit_in_tile(4000, 3000), (256, 192));
+        assert_eq!(fit_in_tile(3000, 4000), (192, 256));
+        assert_eq!(fit_in_tile(10000, 1), (256, 1));
+    }
+
+    #[test]
+    fn test_fit_caption() {
+        assert_eq!(fit_caption("a.jpg"), "a.jpg");
+        let long = format!("{}_end.jpg", "x".repeat(60));
+        let fitted = fit_caption(&long);
+        assert_eq!(fitted.chars().count(), CAPTION_CHARS);
+        assert!(fitted.starts_with("..."));
+        assert!(fitted.ends_with("_end.jpg"));
+    }
+}
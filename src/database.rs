@@ -1,94 +1,532 @@
 use std::{
-    fs::Metadata, time::UNIX_EPOCH
+    fs::Metadata,
+    path::{Path, PathBuf},
+    process::exit,
+    time::UNIX_EPOCH,
 };
 
-use rusqlite::{Connection, params};
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+
+use crate::{
+    cli::Cli,
+    image_match_rs::{DEFAULT_CROP, DEFAULT_GRID_SIZE},
+    shared::{path_from_bytes, path_to_bytes, Hash},
+};
 
 #[derive(Debug)]
 struct SignatureRow {
     id: i64,
-    path: String,
+    path: Vec<u8>,
     modified: i64,
+    modified_ns: Option<i64>,
+    size: Option<i64>,
+    hash: Option<Vec<u8>>,
+    pixel_hash: Option<Vec<u8>>,
     pub signature: Vec<u8>,
 }
 
 #[derive(Debug)]
 pub struct Signature {
     id: i64,
-    path: String,
+    pub path: PathBuf,
     modified: u64,
+    /// The modification time in nanoseconds, missing from signatures stored by
+    /// older versions along with the size and hash.
+    modified_ns: Option<u64>,
+    size: Option<u64>,
+    pub hash: Option<Hash>,
+    /// A hash of the decoded pixels, missing if the image hasn't been decoded
+    /// since they started being hashed.
+    pub pixel_hash: Option<Hash>,
     pub signature: Vec<i8>,
 }
 
-pub fn init(db_conn: &Connection) -> rusqlite::Result<usize> {
+impl Signature {
+    fn from_row(row: SignatureRow) -> Signature {
+        Signature {
+            id: row.id,
+            path: path_from_bytes(row.path),
+            modified: bytemuck::cast::<i64, u64>(row.modified),
+            modified_ns: row.modified_ns.map(bytemuck::cast::<i64, u64>),
+            size: row.size.map(bytemuck::cast::<i64, u64>),
+            hash: row.hash.and_then(|hash| hash.try_into().ok()),
+            pixel_hash: row.pixel_hash.and_then(|hash| hash.try_into().ok()),
+            signature: bytemuck::cast_slice(&row.signature).to_vec(),
+        }
+    }
+
+    /// Whether the file still has the size and modification time it had when
+    /// the signature was stored. Older signatures only have the modification
+    /// time in whole seconds to go by.
+    pub fn is_current(&self, stat: &Metadata) -> bool {
+        let Ok((modified, modified_ns)) = modified_times(stat) else {
+            return false;
+        };
+        match (self.size, self.modified_ns) {
+            (Some(size), Some(ns)) => size == stat.len() && ns == modified_ns,
+            _ => self.modified >= modified,
+        }
+    }
+}
+
+/// The modification time of a file in whole seconds and in nanoseconds.
+fn modified_times(stat: &Metadata) -> anyhow::Result<(u64, u64)> {
+    let since = stat.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok((since.as_secs(), since.as_nanos().try_into()?))
+}
+
+const SIGNATURE_COLUMNS: &str =
+    "rowid, path, modified, modified_ns, size, hash, signature, pixel_hash";
+
+fn signature_row(row: &rusqlite::Row) -> rusqlite::Result<SignatureRow> {
+    Ok(SignatureRow {
+        id: row.get(0)?,
+        path: row.get(1)?,
+        modified: row.get(2)?,
+        modified_ns: row.get(3)?,
+        size: row.get(4)?,
+        hash: row.get(5)?,
+        signature: row.get(6)?,
+        pixel_hash: row.get(7)?,
+    })
+}
+
+/// The file given with `--database-file`, or the default one in the user's
+/// cache directory.
+pub fn database_path(cli: &Cli) -> Option<PathBuf> {
+    cli.database_file
+        .clone()
+        .or_else(|| platform_dirs::AppDirs::new(Some("simagef"), false).map(|v| v.cache_dir))
+}
+
+/// The algorithm and parameters behind the signatures we compute. Rows stored
+/// with any other are treated as missing, so their signatures are computed
+/// again rather than compared with ours.
+pub fn signature_algorithm() -> String {
+    format!(
+        "image-match crop={} grid={}",
+        DEFAULT_CROP, DEFAULT_GRID_SIZE
+    )
+}
+
+/// Opens the database and brings its schema up to date, exiting if either
+/// fails.
+pub fn open(db_path: &Path) -> Connection {
+    let conn = Connection::open(db_path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", db_path.display(), e);
+        exit(1);
+    });
+    if let Err(e) = init(&conn) {
+        eprintln!("{}: {}", db_path.display(), e);
+        exit(1);
+    }
+    conn
+}
+
+/// Changes to the schema, in order. A database at version `n` has had the
+/// first `n` applied. Databases from before the version was recorded are at
+/// version 0 but may already have some of these changes, so they check before
+/// changing anything.
+const MIGRATIONS: &[fn(&Connection) -> rusqlite::Result<()>] = &[
+    create_tables,
+    add_content_columns,
+    add_pixel_hash,
+    add_algorithm,
+    clear_pixel_hashes,
+];
+
+/// Runs the migrations the database hasn't had yet, all in one transaction so
+/// that two processes opening an old database don't both migrate it. Databases
+/// from a newer version of simagef are refused rather than misread.
+pub fn init(db_conn: &Connection) -> anyhow::Result<()> {
+    let tx = Transaction::new_unchecked(db_conn, TransactionBehavior::Immediate)?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS meta (
+                            key   TEXT NOT NULL PRIMARY KEY,
+                            value NOT NULL)",
+        (),
+    )?;
+    let version: i64 = tx
+        .query_row(
+            "SELECT value FROM meta WHERE key = 'schema_version'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0);
+    let version = usize::try_from(version)?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "Database schema version {} is newer than this version of simagef supports ({})",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+
+    for migration in &MIGRATIONS[version..] {
+        migration(&tx)?;
+    }
+    if version < MIGRATIONS.len() {
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('schema_version', ?1)",
+            [MIGRATIONS.len() as i64],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn add_column(conn: &Connection, table: &str, column: &str, kind: &str) -> rusqlite::Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = (?2))",
+        [table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, kind),
+            (),
+        )?;
+    }
+    Ok(())
+}
+
+/// Paths are stored as the raw bytes the OS gave us, so paths that aren't valid
+/// UTF-8 survive the round trip.
+fn create_tables(db_conn: &Connection) -> rusqlite::Result<()> {
     db_conn.execute(
         "CREATE TABLE IF NOT EXISTS signatures (
-                            path      TEXT NOT NULL PRIMARY KEY,
+                            path      BLOB NOT NULL PRIMARY KEY,
                             modified  INTEGER NOT NULL,
                             signature BLOB)",
         (),
-    )
+    )?;
+    db_conn.execute(
+        "CREATE TABLE IF NOT EXISTS lsh_indexes (
+                            params     TEXT NOT NULL PRIMARY KEY,
+                            last_rowid INTEGER NOT NULL,
+                            last_path  BLOB NOT NULL,
+                            data       BLOB NOT NULL)",
+        (),
+    )?;
+    db_conn.execute(
+        "CREATE TABLE IF NOT EXISTS lsh_entries (
+                            params TEXT NOT NULL,
+                            id     INTEGER NOT NULL,
+                            path   BLOB NOT NULL,
+                            PRIMARY KEY (params, id))",
+        (),
+    )?;
+    // Pairs marked as not being duplicates, by the content hashes of the two
+    // images with the lower hash first.
+    db_conn.execute(
+        "CREATE TABLE IF NOT EXISTS ignored_pairs (
+                            hash_a BLOB NOT NULL,
+                            hash_b BLOB NOT NULL,
+                            PRIMARY KEY (hash_a, hash_b))",
+        (),
+    )?;
+    // Older versions stored paths as TEXT, which would never compare equal to
+    // the BLOBs we look up.
+    db_conn.execute(
+        "UPDATE OR REPLACE signatures SET path = CAST(path AS BLOB) WHERE typeof(path) = 'text'",
+        (),
+    )?;
+    Ok(())
 }
 
-pub fn fetch(conn: &Connection, filename: &str, stat: &Metadata) -> anyhow::Result<Option<Signature>> {
-    let mut stmt =
-        conn.prepare("SELECT path, modified, signature FROM signatures WHERE path = (?1)")?;
-    let mut signatures = stmt.query_map([filename], |row| {
-        Ok(SignatureRow {
-            id: 0,
-            path: row.get(0)?,
-            modified: row.get(1)?,
-            signature: row.get(2)?,
-        })
-    })?;
+/// The columns used to recognize files by their content.
+fn add_content_columns(db_conn: &Connection) -> rusqlite::Result<()> {
+    add_column(db_conn, "signatures", "size", "INTEGER")?;
+    add_column(db_conn, "signatures", "modified_ns", "INTEGER")?;
+    add_column(db_conn, "signatures", "hash", "BLOB")?;
+    db_conn.execute(
+        "CREATE INDEX IF NOT EXISTS signatures_content ON signatures (size, hash)",
+        (),
+    )?;
+    Ok(())
+}
 
-    match signatures.next() {
-        Some(sig) => {
-            let sig = sig?;
-            let modified = bytemuck::cast::<i64, u64>(sig.modified);
-            if modified < stat.modified()?.duration_since(UNIX_EPOCH)?.as_secs() {
-                Ok(None)
-            } else {
-                Ok(Some(Signature {
-                    id: sig.id,
-                    path: sig.path,
-                    modified,
-                    signature: bytemuck::cast_slice(&sig.signature).to_vec(),
-                }))
-            }
-        }
-        None => Ok(None),
+fn add_pixel_hash(db_conn: &Connection) -> rusqlite::Result<()> {
+    add_column(db_conn, "signatures", "pixel_hash", "BLOB")
+}
+
+/// Every signature stored before the algorithm was recorded came from the
+/// one below.
+fn add_algorithm(db_conn: &Connection) -> rusqlite::Result<()> {
+    add_column(db_conn, "signatures", "algorithm", "TEXT")?;
+    db_conn.execute(
+        "UPDATE signatures SET algorithm = 'image-match crop=0.05 grid=10' WHERE algorithm IS NULL",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Pixel hashes used to be taken after converting images to 8 bits per
+/// channel, which gave wider images the hash of their 8-bit versions. They're
+/// computed again the next time `--pixel-identical` needs them.
+fn clear_pixel_hashes(db_conn: &Connection) -> rusqlite::Result<()> {
+    db_conn.execute("UPDATE signatures SET pixel_hash = NULL", ())?;
+    Ok(())
+}
+
+/// The stored signature for a path, regardless of whether the file has changed
+/// since. Signatures from another algorithm don't count.
+pub fn fetch_stored(conn: &Connection, filename: &Path) -> anyhow::Result<Option<Signature>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM signatures WHERE path = (?1) AND algorithm = (?2)",
+        SIGNATURE_COLUMNS
+    ))?;
+    let mut rows = stmt.query_map(
+        params![path_to_bytes(filename), signature_algorithm()],
+        signature_row,
+    )?;
+    Ok(rows.next().transpose()?.map(Signature::from_row))
+}
+
+/// A stored signature of a file with the given size and content hash, under
+/// any path.
+pub fn fetch_by_content(
+    conn: &Connection,
+    size: u64,
+    hash: &Hash,
+) -> anyhow::Result<Option<Signature>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM signatures WHERE size = (?1) AND hash = (?2) AND algorithm = (?3) LIMIT 1",
+        SIGNATURE_COLUMNS
+    ))?;
+    let mut rows = stmt.query_map(
+        params![
+            bytemuck::cast::<u64, i64>(size),
+            hash.as_slice(),
+            signature_algorithm()
+        ],
+        signature_row,
+    )?;
+    Ok(rows.next().transpose()?.map(Signature::from_row))
+}
+
+/// Calls `f` with the rowid, path and signature of every row added after
+/// `rowid`, in insertion order. Replaced rows count as added. Signatures from
+/// another algorithm are skipped.
+pub fn fetch_after<F>(conn: &Connection, rowid: i64, mut f: F) -> anyhow::Result<()>
+where
+    F: FnMut(i64, Vec<u8>, Vec<i8>),
+{
+    let mut stmt = conn.prepare(
+        "SELECT rowid, path, signature FROM signatures
+                        WHERE rowid > (?1) AND algorithm = (?2) ORDER BY rowid",
+    )?;
+    let mut rows = stmt.query(params![rowid, signature_algorithm()])?;
+    while let Some(row) = rows.next()? {
+        let signature: Vec<u8> = row.get(2)?;
+        f(
+            row.get(0)?,
+            row.get(1)?,
+            bytemuck::cast_slice(&signature).to_vec(),
+        );
     }
+    Ok(())
 }
 
-pub struct InsertionMessage {
-    pub filename_s: String,
-    pub stat: Metadata,
-    pub signature: Vec<i8>,
+/// The path stored at `rowid`, used to notice when rowids have been reused.
+pub fn path_at_rowid(conn: &Connection, rowid: i64) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut stmt = conn.prepare("SELECT path FROM signatures WHERE rowid = (?1)")?;
+    let mut rows = stmt.query_map([rowid], |row| row.get(0))?;
+    Ok(rows.next().transpose()?)
 }
 
-pub fn insert_batch (
+pub fn fetch_ignored(conn: &Connection) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut stmt = conn.prepare("SELECT hash_a, hash_b FROM ignored_pairs")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Adds or removes ignored pairs, given as content hashes with the lower one
+/// first.
+pub fn store_ignored(
     conn: &mut Connection,
-    messages: &Vec<InsertionMessage>,
+    pairs: &[(&[u8], &[u8])],
+    ignored: bool,
 ) -> anyhow::Result<()> {
+    let tx = conn.transaction()?;
+    for (hash_a, hash_b) in pairs {
+        if ignored {
+            tx.execute(
+                "INSERT OR IGNORE INTO ignored_pairs (hash_a, hash_b) VALUES (?1, ?2)",
+                params![hash_a, hash_b],
+            )?;
+        } else {
+            tx.execute(
+                "DELETE FROM ignored_pairs WHERE hash_a = (?1) AND hash_b = (?2)",
+                params![hash_a, hash_b],
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
 
+/// The length of every signature computed with `signature_algorithm`.
+pub const SIGNATURE_LEN: usize = 625;
+
+/// A stored row, without the signature itself.
+pub struct Entry {
+    pub id: i64,
+    pub path: PathBuf,
+    pub modified: u64,
+    /// The length of the signature in bytes, if there is one.
+    pub signature_len: Option<usize>,
+    pub algorithm: Option<String>,
+}
+
+pub fn fetch_entries(conn: &Connection) -> anyhow::Result<Vec<Entry>> {
+    let mut stmt =
+        conn.prepare("SELECT rowid, path, modified, length(signature), algorithm FROM signatures")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, Vec<u8>>(1)?,
+            row.get::<_, i64>(2)?,
+            row.get::<_, Option<i64>>(3)?,
+            row.get::<_, Option<String>>(4)?,
+        ))
+    })?;
+    let mut entries = Vec::new();
+    for row in rows {
+        let (id, path, modified, signature_len, algorithm) = row?;
+        entries.push(Entry {
+            id,
+            path: path_from_bytes(path),
+            modified: bytemuck::cast::<i64, u64>(modified),
+            signature_len: signature_len.map(|len| len as usize),
+            algorithm,
+        });
+    }
+    Ok(entries)
+}
+
+/// Deletes the rows with the given rowids.
+pub fn delete_entries(conn: &mut Connection, ids: &[i64]) -> anyhow::Result<()> {
     let tx = conn.transaction()?;
+    for id in ids {
+        tx.execute("DELETE FROM signatures WHERE rowid = (?1)", [id])?;
+    }
+    tx.commit()?;
+    Ok(())
+}
 
-    for msg in messages {
-        let filename = &msg.filename_s;
-        let modified = msg.stat.modified()?;
-        let since: u64 = modified.duration_since(UNIX_EPOCH)?.as_secs().try_into()?;
-        let since = bytemuck::cast::<u64, i64>(since);
-        let signature = bytemuck::cast_slice::<i8, u8>(&msg.signature).to_vec();
+/// Rebuilds the database file to reclaim the space left by deleted rows. This
+/// can give rows new rowids, which the stored indexes rely on, so those are
+/// dropped to be rebuilt when next used.
+pub fn vacuum(conn: &Connection) -> anyhow::Result<()> {
+    conn.execute("DELETE FROM lsh_entries", ())?;
+    conn.execute("DELETE FROM lsh_indexes", ())?;
+    conn.execute("VACUUM", ())?;
+    Ok(())
+}
+
+/// The problems SQLite finds with the database file, if any.
+pub fn integrity_check(conn: &Connection) -> anyhow::Result<Vec<String>> {
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let messages = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(messages
+        .into_iter()
+        .filter(|message| message != "ok")
+        .collect())
+}
+
+/// A serialized LSH index and the last signature row it includes.
+pub struct StoredIndex {
+    pub last_rowid: i64,
+    pub last_path: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+pub fn fetch_index(conn: &Connection, key: &str) -> anyhow::Result<Option<StoredIndex>> {
+    let mut stmt =
+        conn.prepare("SELECT last_rowid, last_path, data FROM lsh_indexes WHERE params = (?1)")?;
+    let mut rows = stmt.query_map([key], |row| {
+        Ok(StoredIndex {
+            last_rowid: row.get(0)?,
+            last_path: row.get(1)?,
+            data: row.get(2)?,
+        })
+    })?;
+    Ok(rows.next().transpose()?)
+}
+
+/// The path of every entry in a stored index, ordered by id.
+pub fn fetch_index_paths(conn: &Connection, key: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let mut stmt = conn.prepare("SELECT path FROM lsh_entries WHERE params = (?1) ORDER BY id")?;
+    let rows = stmt.query_map([key], |row| row.get::<_, Vec<u8>>(0))?;
+    let mut paths = Vec::new();
+    for row in rows {
+        paths.push(path_from_bytes(row?));
+    }
+    Ok(paths)
+}
+
+/// Stores an index along with the entries added since it was last stored, which
+/// start at id `first_id`. A `first_id` of 0 replaces all entries.
+pub fn store_index(
+    conn: &mut Connection,
+    key: &str,
+    index: &StoredIndex,
+    first_id: usize,
+    paths: &[PathBuf],
+) -> anyhow::Result<()> {
+    let tx = conn.transaction()?;
 
+    if first_id == 0 {
+        tx.execute("DELETE FROM lsh_entries WHERE params = (?1)", [key])?;
+    }
+    for (id, path) in paths.iter().enumerate().skip(first_id) {
         tx.execute(
-            "INSERT OR REPLACE INTO signatures
-                            (path, modified, signature)
-                            VALUES
-                            (?1, ?2, ?3)",
-            params![filename, since, signature],
+            "INSERT OR REPLACE INTO lsh_entries (params, id, path) VALUES (?1, ?2, ?3)",
+            params![key, id as i64, path_to_bytes(path)],
         )?;
     }
+    tx.execute(
+        "INSERT OR REPLACE INTO lsh_indexes
+                        (params, last_rowid, last_path, data)
+                        VALUES
+                        (?1, ?2, ?3, ?4)",
+        params![key, index.last_rowid, index.last_path, index.data],
+    )?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+pub struct InsertionMessage {
+    pub filename: PathBuf,
+    pub stat: Metadata,
+    pub hash: Option<Hash>,
+    /// The signature to store, or `None` if the stored one is still right and
+    /// only the size, modification time and hash need updating.
+    pub signature: Option<Vec<i8>>,
+    pub pixel_hash: Option<Hash>,
+}
+
+pub fn insert_batch(conn: &mut Connection, messages: &Vec<InsertionMessage>) -> anyhow::Result<()> {
+    let tx = conn.transaction()?;
+
+    for msg in messages {
+        match &msg.signature {
+            Some(signature) => insert(
+                &tx,
+                &msg.filename,
+                &msg.stat,
+                msg.hash.as_ref(),
+                msg.pixel_hash.as_ref(),
+                signature,
+            )?,
+            None => refresh(&tx, &msg.filename, &msg.stat, msg.hash.as_ref())?,
+        };
+    }
 
     tx.commit()?;
     Ok(())
@@ -96,20 +534,134 @@ pub fn insert_batch (
 
 pub fn insert(
     conn: &Connection,
-    filename: &str,
+    filename: &Path,
     stat: &Metadata,
+    hash: Option<&Hash>,
+    pixel_hash: Option<&Hash>,
     signature: &[i8],
 ) -> anyhow::Result<usize> {
-    let modified = stat.modified()?;
-    let since: u64 = modified.duration_since(UNIX_EPOCH)?.as_secs().try_into()?;
-    let since = bytemuck::cast::<u64, i64>(since);
+    let (modified, modified_ns) = modified_times(stat)?;
     let signature = bytemuck::cast_slice::<i8, u8>(signature).to_vec();
 
     Ok(conn.execute(
         "INSERT OR REPLACE INTO signatures
-                        (path, modified, signature)
+                        (path, modified, signature, size, modified_ns, hash, pixel_hash, algorithm)
                         VALUES
-                        (?1, ?2, ?3)",
-        params![filename, since, signature],
+                        (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            path_to_bytes(filename),
+            bytemuck::cast::<u64, i64>(modified),
+            signature,
+            bytemuck::cast::<u64, i64>(stat.len()),
+            bytemuck::cast::<u64, i64>(modified_ns),
+            hash.map(|hash| hash.as_slice()),
+            pixel_hash.map(|hash| hash.as_slice()),
+            signature_algorithm(),
+        ],
+    )?)
+}
+
+/// Updates the size, modification time and hash stored for a file whose
+/// content hasn't changed. The row keeps its rowid, so indexes over the
+/// signatures don't take it for a new one.
+fn refresh(
+    conn: &Connection,
+    filename: &Path,
+    stat: &Metadata,
+    hash: Option<&Hash>,
+) -> anyhow::Result<usize> {
+    let (modified, modified_ns) = modified_times(stat)?;
+
+    Ok(conn.execute(
+        "UPDATE signatures SET modified = (?2), size = (?3), modified_ns = (?4), hash = (?5)
+                        WHERE path = (?1)",
+        params![
+            path_to_bytes(filename),
+            bytemuck::cast::<u64, i64>(modified),
+            bytemuck::cast::<u64, i64>(stat.len()),
+            bytemuck::cast::<u64, i64>(modified_ns),
+            hash.map(|hash| hash.as_slice()),
+        ],
     )?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_content() {
+        let conn = Connection::open_in_memory().unwrap();
+        init(&conn).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("simagef-database-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("a.png");
+        std::fs::write(&file, b"a").unwrap();
+        let stat = std::fs::metadata(&file).unwrap();
+        let hash = crate::shared::content_hash(&file).unwrap();
+        insert(&conn, &file, &stat, Some(&hash), None, &[1, -2, 3]).unwrap();
+
+        let stored = fetch_stored(&conn, &file).unwrap().unwrap();
+        assert!(stored.is_current(&stat));
+        assert_eq!(stored.hash, Some(hash));
+        assert_eq!(stored.signature, vec![1, -2, 3]);
+
+        // Same modification time, different size.
+        std::fs::write(&file, b"ab").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(stat.modified().unwrap())
+            .unwrap();
+        assert!(!stored.is_current(&std::fs::metadata(&file).unwrap()));
+
+        let moved = fetch_by_content(&conn, 1, &hash).unwrap().unwrap();
+        assert_eq!(moved.path, file);
+        assert!(fetch_by_content(&conn, 2, &hash).unwrap().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrations() {
+        // A database from before the schema version was recorded.
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE signatures (path TEXT NOT NULL PRIMARY KEY, modified INTEGER NOT NULL, signature BLOB)",
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO signatures (path, modified, signature) VALUES ('/a.png', 1, x'01fe03')",
+            (),
+        )
+        .unwrap();
+        init(&conn).unwrap();
+        init(&conn).unwrap();
+
+        let stored = fetch_stored(&conn, Path::new("/a.png")).unwrap().unwrap();
+        assert_eq!(stored.signature, vec![1, -2, 3]);
+        let version: i64 = conn
+            .query_row(
+                "SELECT value FROM meta WHERE key = 'schema_version'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+
+        conn.execute("UPDATE signatures SET algorithm = 'other'", ())
+            .unwrap();
+        assert!(fetch_stored(&conn, Path::new("/a.png")).unwrap().is_none());
+
+        conn.execute(
+            "UPDATE meta SET value = value + 1 WHERE key = 'schema_version'",
+            (),
+        )
+        .unwrap();
+        assert!(init(&conn).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+use crossbeam::channel::{Receiver, Sender};
+use rusqlite::Connection;
+
+use crate::{
+    database,
+    formatting::{Group, Match},
+    input::InputFile,
+    shared::{content_hash, Hash},
+    sniff::{sniff_format, SniffStats},
+};
+
+struct Candidate {
+    path: PathBuf,
+    canonical: PathBuf,
+    stat: Metadata,
+    /// Only known once another file turns up with the same size.
+    hash: Option<Option<Hash>>,
+}
+
+impl Candidate {
+    /// The hash stored in the database if the file hasn't changed since, or
+    /// otherwise the hash of its content, worked out once.
+    fn hash(&mut self, conn: Option<&Connection>) -> Option<Hash> {
+        if self.hash.is_none() {
+            let stored = conn
+                .and_then(|conn| database::fetch_stored(conn, &self.canonical).ok().flatten())
+                .filter(|stored| stored.is_current(&self.stat))
+                .and_then(|stored| stored.hash);
+            self.hash = Some(stored.or_else(|| content_hash(&self.canonical).ok()));
+        }
+        self.hash.flatten()
+    }
+}
+
+/// A set of files with the same content. Only the first file to arrive is
+/// passed on, and only if it's an image are the others held back as copies.
+struct ExactSet {
+    first: usize,
+    is_image: Option<bool>,
+    copies: Vec<usize>,
+}
+
+/// Finds files that are byte for byte the same before any image is decoded.
+/// Each file is passed on to `filename_tx` as soon as it arrives, unless it
+/// is a copy of an image that was already passed on, or `only_exact` is set.
+/// Files are only hashed once another file with the same size turns up. The
+/// copies are counted as done.
+///
+/// Returns the sets of duplicates, each starting with the file that was
+/// passed on, followed by its copies sorted by path.
+pub fn spawn_exact_pass(
+    input_rx: Receiver<InputFile>,
+    filename_tx: Sender<InputFile>,
+    calc_count_tx: Sender<u64>,
+    db_path: Option<PathBuf>,
+    sniff_stats: Arc<SniffStats>,
+    only_exact: bool,
+) -> JoinHandle<Vec<Vec<PathBuf>>> {
+    thread::spawn(move || {
+        let conn = db_path.and_then(|db_path| Connection::open(db_path).ok());
+        let mut candidates: Vec<Candidate> = Vec::new();
+        let mut seen: HashSet<PathBuf> = HashSet::new();
+        let mut by_size: HashMap<u64, Vec<usize>> = HashMap::new();
+        let mut by_hash: HashMap<(u64, Hash), ExactSet> = HashMap::new();
+        let mut done = 0;
+        let forward = |input: InputFile, done: &mut u64| {
+            if only_exact {
+                sniff_stats.check(&input.path).ok();
+                *done += 1;
+            } else {
+                filename_tx
+                    .send(input)
+                    .expect("Unable to send filename to channel");
+            }
+        };
+
+        while let Ok(input) = input_rx.recv() {
+            let canonical = std::fs::canonicalize(&input.path);
+            let (stat, canonical) = match canonical
+                .and_then(|canonical| Ok((std::fs::metadata(&canonical)?, canonical)))
+            {
+                // The same file given twice isn't a copy of itself.
+                Ok((_, canonical)) if seen.contains(&canonical) => {
+                    done += 1;
+                    continue;
+                }
+                Ok(found) => found,
+                Err(e) if only_exact => {
+                    eprintln!("{}: {}", input.path.display(), e);
+                    done += 1;
+                    continue;
+                }
+                // Left for the signature threads to report.
+                Err(_) => {
+                    forward(input, &mut done);
+                    continue;
+                }
+            };
+            seen.insert(canonical.clone());
+            let size = stat.len();
+            let index = candidates.len();
+            candidates.push(Candidate {
+                path: input.path.clone(),
+                canonical,
+                stat,
+                hash: None,
+            });
+
+            let same_size = by_size.entry(size).or_default();
+            same_size.push(index);
+            let mut is_copy = false;
+            if same_size.len() > 1 {
+                // The first file of this size wasn't hashed when it arrived.
+                if same_size.len() == 2 {
+                    let first = same_size[0];
+                    if let Some(hash) = candidates[first].hash(conn.as_ref()) {
+                        by_hash.entry((size, hash)).or_insert(ExactSet {
+                            first,
+                            is_image: None,
+                            copies: Vec::new(),
+                        });
+                    }
+                }
+                if let Some(hash) = candidates[index].hash(conn.as_ref()) {
+                    match by_hash.entry((size, hash)) {
+                        Entry::Occupied(mut set) => {
+                            let set = set.get_mut();
+                            let first = &candidates[set.first].canonical;
+                            let is_image = *set
+                                .is_image
+                                .get_or_insert_with(|| matches!(sniff_format(first), Ok(Some(_))));
+                            if is_image {
+                                set.copies.push(index);
+                                is_copy = true;
+                            }
+                        }
+                        Entry::Vacant(entry) => {
+                            entry.insert(ExactSet {
+                                first: index,
+                                is_image: None,
+                                copies: Vec::new(),
+                            });
+                        }
+                    }
+                }
+            }
+
+            if is_copy {
+                sniff_stats.check(&input.path).ok();
+                done += 1;
+            } else {
+                forward(input, &mut done);
+            }
+            if done >= 5 && calc_count_tx.try_send(done).is_ok() {
+                done = 0;
+            }
+        }
+        calc_count_tx.send(done).ok();
+
+        let mut exact: Vec<Vec<PathBuf>> = by_hash
+            .into_values()
+            .filter(|set| !set.copies.is_empty())
+            .map(|set| {
+                let mut copies: Vec<PathBuf> = set
+                    .copies
+                    .iter()
+                    .map(|i| candidates[*i].path.clone())
+                    .collect();
+                copies.sort();
+                std::iter::once(candidates[set.first].path.clone())
+                    .chain(copies)
+                    .collect()
+            })
+            .collect();
+        exact.sort();
+        exact
+    })
+}
+
+/// Adds the copies of each image to its group, with a pair for each copy, and
+/// makes a group of every set of duplicates whose image matched nothing else.
+/// Groups keep their first member when `keep_first` is set, and are otherwise
+/// sorted by path like the groups they are merged into.
+pub fn merge_exact<'a>(
+    mut groups: Vec<Group<'a>>,
+    exact: &'a [Vec<PathBuf>],
+    keep_first: bool,
+) -> Vec<Group<'a>> {
+    let mut copies: HashMap<&Path, &[PathBuf]> = exact
+        .iter()
+        .map(|set| (set[0].as_path(), &set[1..]))
+        .collect();
+    let exact_pair = |a: &'a Path, b: &'a Path| (a.min(b), a.max(b), 1.0, Match::Exact);
+
+    for group in &mut groups {
+        let mut added = Vec::new();
+        for member in &group.members {
+            if let Some(set) = copies.remove(member) {
+                for copy in set {
+                    added.push(copy.as_path());
+                    group.pairs.push(exact_pair(member, copy));
+                }
+            }
+        }
+        group.members.extend(added);
+        group.members[usize::from(keep_first)..].sort();
+        group.pairs.sort_by(|a, b| {
+            b.2.total_cmp(&a.2)
+                .then_with(|| (a.0, a.1).cmp(&(b.0, b.1)))
+        });
+    }
+
+    for set in exact {
+        if copies.contains_key(set[0].as_path()) {
+            groups.push(Group {
+                members: set.iter().map(|path| path.as_path()).collect(),
+                pairs: set[1..]
+                    .iter()
+                    .map(|copy| exact_pair(&set[0], copy))
+                    .collect(),
+                keeper: None,
+            });
+        }
+    }
+    groups.sort_by(|a, b| a.members[0].cmp(b.members[0]));
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::InputSet;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    #[test]
+    fn test_exact_pass() {
+        let dir = std::env::temp_dir().join(format!("simagef-exact-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let png = b"\x89PNG\r\n\x1a\nsame";
+        let files = [
+            ("b.png", &png[..]),
+            ("a.png", &png[..]),
+            ("c.png", b"\x89PNG\r\n\x1a\nrest"),
+            ("d.txt", b"text"),
+            ("e.txt", b"text"),
+        ];
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+
+        let (input_tx, input_rx) = crossbeam::channel::unbounded();
+        let (filename_tx, filename_rx) = crossbeam::channel::unbounded();
+        let (count_tx, count_rx) = crossbeam::channel::unbounded();
+        let pass = spawn_exact_pass(
+            input_rx,
+            filename_tx,
+            count_tx,
+            None,
+            Arc::new(SniffStats::default()),
+            false,
+        );
+        let send = |name: &str| {
+            input_tx
+                .send(InputFile {
+                    path: dir.join(name),
+                    set: InputSet::All,
+                })
+                .unwrap()
+        };
+
+        // Files are passed on before the input ends.
+        send("b.png");
+        let first = filename_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(first.path, dir.join("b.png"));
+        for (name, _) in &files[1..] {
+            send(name);
+        }
+        send("b.png");
+        drop(input_tx);
+
+        let exact = pass.join().unwrap();
+        let forwarded: Vec<PathBuf> = filename_rx.iter().map(|input| input.path).collect();
+        assert_eq!(
+            forwarded,
+            vec![dir.join("c.png"), dir.join("d.txt"), dir.join("e.txt")]
+        );
+        assert_eq!(exact, vec![vec![dir.join("b.png"), dir.join("a.png")]]);
+        // The copy and the file given twice.
+        assert_eq!(count_rx.iter().sum::<u64>(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_exact() {
+        let paths = [Path::new("/a.jpg"), Path::new("/b.jpg")];
+        let exact = vec![
+            vec![PathBuf::from("/b.jpg"), PathBuf::from("/a copy.jpg")],
+            vec![PathBuf::from("/d.jpg"), PathBuf::from("/e.jpg")],
+        ];
+        let groups = vec![Group {
+            members: paths.to_vec(),
+            pairs: vec![(paths[0], paths[1], 0.95, Match::Perceptual)],
+            keeper: None,
+        }];
+
+        let merged = merge_exact(groups, &exact, false);
+        let members: Vec<Vec<&Path>> = merged.iter().map(|group| group.members.clone()).collect();
+        assert_eq!(
+            members,
+            vec![
+                vec![Path::new("/a copy.jpg"), paths[0], paths[1]],
+                vec![Path::new("/d.jpg"), Path::new("/e.jpg")],
+            ]
+        );
+        assert_eq!(
+            merged[0].pairs,
+            vec![
+                (Path::new("/a copy.jpg"), paths[1], 1.0, Match::Exact),
+                (paths[0], paths[1], 0.95, Match::Perceptual),
+            ]
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+
+use crate::cli::Cli;
+
+/// Per-directory ignore files with gitignore syntax.
+pub const IGNORE_FILENAME: &str = ".simagefignore";
+
+/// Decides which input paths are passed on to the signature threads, based on
+/// the `--include`, `--exclude`, `--ext` and `--ignore-file` options and the
+/// `.simagefignore` files in the directories above each file.
+#[derive(Debug)]
+pub struct InputFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    extensions: Vec<String>,
+    ignore_files: Vec<Gitignore>,
+    /// The `.simagefignore` file of each directory looked at so far, if it
+    /// has one.
+    dir_ignores: Mutex<HashMap<PathBuf, Option<Gitignore>>>,
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+/// Globs are matched against both the full path and the file name, so `*.xmp`
+/// and `@eaDir` work without a leading `**/`.
+fn glob_match(set: &GlobSet, path: &Path) -> bool {
+    set.is_match(path) || path.file_name().is_some_and(|name| set.is_match(name))
+}
+
+/// Whether an ignore file ignores a file, or `None` if it doesn't say. As in
+/// git, a file in an ignored directory is ignored whatever the file's own
+/// pattern says. Only directories below the one the ignore file is in count.
+fn gitignore_match(ignore: &Gitignore, path: &Path) -> Option<bool> {
+    for dir in path.ancestors().skip(1) {
+        if dir == ignore.path() {
+            break;
+        }
+        if ignore.matched(dir, true).is_ignore() {
+            return Some(true);
+        }
+    }
+    match ignore.matched(path, false) {
+        ignore::Match::Ignore(_) => Some(true),
+        ignore::Match::Whitelist(_) => Some(false),
+        ignore::Match::None => None,
+    }
+}
+
+impl InputFilter {
+    pub fn new(
+        include: &[String],
+        exclude: &[String],
+        extensions: &[String],
+    ) -> Result<InputFilter, globset::Error> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(build_glob_set(include)?)
+        };
+        let extensions = extensions
+            .iter()
+            .map(|ext| ext.trim_start_matches('.').to_lowercase())
+            .collect();
+
+        Ok(InputFilter {
+            include,
+            exclude: build_glob_set(exclude)?,
+            extensions,
+            ignore_files: Vec::new(),
+            dir_ignores: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Adds ignore files that apply to every file, like those given with
+    /// `--ignore-file`. Problems with them are reported, and the patterns
+    /// that could be read are still used.
+    pub fn with_ignore_files(mut self, paths: &[PathBuf]) -> InputFilter {
+        for path in paths {
+            let (ignore, error) = Gitignore::new(path);
+            if let Some(e) = error {
+                eprintln!("{}: {}", path.display(), e);
+            }
+            self.ignore_files.push(ignore);
+        }
+        self
+    }
+
+    pub fn from_cli(cli: &Cli) -> Result<InputFilter, globset::Error> {
+        Ok(InputFilter::new(&cli.include, &cli.exclude, &cli.ext)?
+            .with_ignore_files(&cli.ignore_file))
+    }
+
+    /// Whether the ignore files say to skip a file. Walking directories
+    /// applies them already, but files from stdin or the arguments haven't
+    /// been through a walk. The closest `.simagefignore` that mentions the
+    /// file decides, followed by the ignore files given.
+    fn is_ignored(&self, path: &Path) -> bool {
+        let Ok(path) = std::path::absolute(path) else {
+            return false;
+        };
+        let mut dir_ignores = self
+            .dir_ignores
+            .lock()
+            .expect("Unable to lock ignore files");
+        for dir in path.ancestors().skip(1) {
+            let ignore = dir_ignores.entry(dir.to_path_buf()).or_insert_with(|| {
+                let file = dir.join(IGNORE_FILENAME);
+                file.is_file().then(|| Gitignore::new(file).0)
+            });
+            let ignored = ignore
+                .as_ref()
+                .and_then(|ignore| gitignore_match(ignore, &path));
+            if let Some(ignored) = ignored {
+                return ignored;
+            }
+        }
+        self.ignore_files
+            .iter()
+            .find_map(|ignore| gitignore_match(ignore, &path))
+            .unwrap_or(false)
+    }
+
+    /// Whether a file should be compared. Excludes also apply to the
+    /// directories the file is in.
+    pub fn accepts_file(&self, path: &Path) -> bool {
+        if !self.extensions.is_empty() {
+            let extension = path
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase());
+            match extension {
+                Some(extension) if self.extensions.contains(&extension) => (),
+                _ => return false,
+            }
+        }
+
+        if let Some(include) = &self.include {
+            if !glob_match(include, path) {
+                return false;
+            }
+        }
+
+        // Files from stdin or the arguments weren't found by walking, so the
+        // directories they're in haven't been checked yet.
+        !path
+            .ancestors()
+            .any(|ancestor| glob_match(&self.exclude, ancestor))
+            && !self.is_ignored(path)
+    }
+
+    /// Whether a directory should be descended into while walking.
+    pub fn accepts_dir(&self, path: &Path) -> bool {
+        !glob_match(&self.exclude, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str], extensions: &[&str]) -> InputFilter {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        InputFilter::new(&strings(include), &strings(exclude), &strings(extensions)).unwrap()
+    }
+
+    #[test]
+    fn test_accepts_everything_by_default() {
+        let filter = filter(&[], &[], &[]);
+        assert!(filter.accepts_file(Path::new("/photos/a.jpg")));
+        assert!(filter.accepts_file(Path::new("/photos/notes.txt")));
+        assert!(filter.accepts_dir(Path::new("/photos/@eaDir")));
+    }
+
+    #[test]
+    fn test_extensions() {
+        let filter = filter(&[], &[], &["jpg", ".PNG"]);
+        assert!(filter.accepts_file(Path::new("/photos/a.JPG")));
+        assert!(filter.accepts_file(Path::new("/photos/b.png")));
+        assert!(!filter.accepts_file(Path::new("/photos/a.xmp")));
+        assert!(!filter.accepts_file(Path::new("/photos/README")));
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let filter = filter(&["*.jpg", "*.webp"], &["*_thumb.*", "@eaDir"], &[]);
+        assert!(filter.accepts_file(Path::new("/photos/a.jpg")));
+        assert!(!filter.accepts_file(Path::new("/photos/a_thumb.jpg")));
+        assert!(!filter.accepts_file(Path::new("/photos/a.txt")));
+        assert!(!filter.accepts_dir(Path::new("/photos/@eaDir")));
+        assert!(filter.accepts_dir(Path::new("/photos/2024")));
+    }
+
+    #[test]
+    fn test_exclude_directory_of_listed_file() {
+        // As with `find lib -type f | simagef - --exclude @eaDir`.
+        let filter = filter(&[], &["@eaDir"], &[]);
+        assert!(!filter.accepts_file(Path::new("lib/@eaDir/thumb.png")));
+        assert!(!filter.accepts_file(Path::new("/photos/@eaDir/2024/thumb.png")));
+        assert!(filter.accepts_file(Path::new("lib/eaDir/thumb.png")));
+    }
+
+    #[test]
+    fn test_ignore_files() {
+        let dir = std::env::temp_dir().join(format!("simagef-filter-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib/raw")).unwrap();
+        std::fs::write(dir.join(IGNORE_FILENAME), "*.tmp.png\nraw/\n").unwrap();
+        std::fs::write(dir.join("lib").join(IGNORE_FILENAME), "!keep.tmp.png\n").unwrap();
+        let extra = dir.join("extra-ignore");
+        std::fs::write(&extra, "*_thumb.png\n").unwrap();
+
+        // As with `find . -type f | simagef -` from within the directory.
+        let filter = filter(&[], &[], &[]).with_ignore_files(&[extra]);
+        assert!(filter.accepts_file(&dir.join("lib/a.png")));
+        assert!(!filter.accepts_file(&dir.join("lib/a.tmp.png")));
+        assert!(filter.accepts_file(&dir.join("lib/keep.tmp.png")));
+        assert!(!filter.accepts_file(&dir.join("lib/raw/b.png")));
+        assert!(!filter.accepts_file(&dir.join("lib/a_thumb.png")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
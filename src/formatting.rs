@@ -1,23 +1,640 @@
-use crate::cli::Fmt;
+use std::{
+    collections::HashMap,
+    fmt::{Display, Write as _},
+    io::Write,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
 
-pub fn print_fmt(group: &Vec<&str>, fmt: Fmt) {
+use serde::Serialize;
+
+use crate::{cli::Fmt, open_image::image_dimensions, shared::path_to_bytes};
+
+/// Paths are written as raw bytes, so names that aren't valid UTF-8 come out
+/// exactly as they are on disk.
+fn print_fmt(group: &[&Path], fmt: Fmt) {
+    write_line(Vec::new(), group, fmt);
+}
+
+/// Same as `print_fmt`, with the similarity score as the first field.
+fn print_scored(group: &[&Path], score: f64, fmt: Fmt) {
+    let mut line = format!("{:.4}", score).into_bytes();
+    match fmt {
+        Fmt::Null => line.push(b'\0'),
+        _ => line.push(b' '),
+    }
+    write_line(line, group, fmt);
+}
+
+fn write_line(mut line: Vec<u8>, group: &[&Path], fmt: Fmt) {
     match fmt {
         Fmt::Regular => {
-            println!("{}", group.join(" "));
+            for (i, path) in group.iter().enumerate() {
+                if i > 0 {
+                    line.push(b' ');
+                }
+                line.extend_from_slice(path_to_bytes(path));
+            }
+            line.push(b'\n');
         }
         Fmt::Quote => {
-            let lines: Vec<String> = group
-                .into_iter()
-                .map(|s| format!("\"{}\"", s.replace("\"", "\\\"")))
-                .collect();
-            let line = lines.join(" ");
-            println!("{}", line);
+            for (i, path) in group.iter().enumerate() {
+                if i > 0 {
+                    line.push(b' ');
+                }
+                line.push(b'"');
+                for byte in path_to_bytes(path) {
+                    if *byte == b'"' {
+                        line.push(b'\\');
+                    }
+                    line.push(*byte);
+                }
+                line.push(b'"');
+            }
+            line.push(b'\n');
         }
         Fmt::Null => {
-            for filename in group {
-                print!("{}\0", filename);
+            for path in group {
+                line.extend_from_slice(path_to_bytes(path));
+                line.push(b'\0');
             }
-            print!("\0");
+            line.push(b'\0');
         }
+        Fmt::Json | Fmt::Jsonl | Fmt::Csv | Fmt::Tsv | Fmt::Dot | Fmt::Graphml => {
+            unreachable!("Structured formats are not written line by line")
+        }
+    }
+    write_bytes(&line);
+}
+
+fn write_bytes(bytes: &[u8]) {
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(bytes).expect("Unable to write to stdout");
+    // NUL separated groups don't end in a newline, which is when stdout
+    // would flush on its own.
+    stdout.flush().expect("Unable to write to stdout");
+}
+
+/// An image as it appears in structured output. Details are left out if the
+/// file can no longer be read.
+#[derive(Serialize)]
+struct ImageRecord {
+    path: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    size: Option<u64>,
+    /// Seconds since the Unix epoch.
+    modified: Option<u64>,
+    /// Only written for the image picked with `--keep`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    keeper: bool,
+}
+
+impl ImageRecord {
+    fn new(path: &Path) -> ImageRecord {
+        let dimensions = image_dimensions(path).ok();
+        let stat = std::fs::metadata(path).ok();
+        ImageRecord {
+            path: path.to_string_lossy().into_owned(),
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            size: stat.as_ref().map(|stat| stat.len()),
+            modified: stat
+                .and_then(|stat| stat.modified().ok())
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs()),
+            keeper: false,
+        }
+    }
+}
+
+/// Formats a Unix timestamp as a UTC date and time that spreadsheets can
+/// parse, such as `2024-05-01 13:45:00`.
+pub fn format_time(secs: u64) -> String {
+    // Converts days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = secs / 86400 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Escapes text for HTML and XML.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            // Not allowed in XML, even escaped.
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {
+                escaped.push(char::REPLACEMENT_CHARACTER)
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quotes an ID or attribute for the DOT language.
+fn dot_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+const GRAPHML_HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns">
+  <key id="label" for="node" attr.name="label" attr.type="string"/>
+  <key id="path" for="node" attr.name="path" attr.type="string"/>
+  <key id="group" for="node" attr.name="group" attr.type="int"/>
+  <key id="keeper" for="node" attr.name="keeper" attr.type="boolean"/>
+  <key id="weight" for="edge" attr.name="weight" attr.type="double"/>
+  <key id="match" for="edge" attr.name="match" attr.type="string"/>
+  <graph id="similar" edgedefault="undirected">
+"#;
+
+const TABLE_HEADER: [&str; 14] = [
+    "path_a", "path_b", "score", "group_id", "width_a", "height_a", "size_a", "mtime_a", "width_b",
+    "height_b", "size_b", "mtime_b", "keeper", "match",
+];
+
+/// Writes a field of a CSV or TSV row, in quotemarks as described in RFC 4180
+/// if it contains the delimiter, a quotemark or a line break.
+fn push_field(row: &mut Vec<u8>, field: &[u8], delimiter: u8) {
+    if field
+        .iter()
+        .any(|byte| matches!(*byte, b'"' | b'\n' | b'\r') || *byte == delimiter)
+    {
+        row.push(b'"');
+        for byte in field {
+            if *byte == b'"' {
+                row.push(b'"');
+            }
+            row.push(*byte);
+        }
+        row.push(b'"');
+    } else {
+        row.extend_from_slice(field);
+    }
+}
+
+#[derive(Serialize)]
+struct PairRecord {
+    a: String,
+    b: String,
+    score: f64,
+    #[serde(rename = "match")]
+    kind: Match,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record {
+    Pair {
+        score: f64,
+        #[serde(rename = "match")]
+        kind: Match,
+        images: [ImageRecord; 2],
+    },
+    Group {
+        images: Vec<ImageRecord>,
+        pairs: Vec<PairRecord>,
+    },
+}
+
+/// How the images of a pair were found to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Match {
+    /// The files are the same byte for byte.
+    Exact,
+    /// The decoded images have the same dimensions and pixels.
+    #[serde(rename = "pixel-identical")]
+    Pixel,
+    /// The signatures of the images are similar.
+    Perceptual,
+}
+
+impl Display for Match {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Match::Exact => f.write_str("exact"),
+            Match::Pixel => f.write_str("pixel-identical"),
+            Match::Perceptual => f.write_str("perceptual"),
+        }
+    }
+}
+
+/// Two matched images, their score and how they matched.
+pub type Pair<'a> = (&'a Path, &'a Path, f64, Match);
+
+/// A group of similar images along with the pairs that connected them, best
+/// match first.
+pub struct Group<'a> {
+    pub members: Vec<&'a Path>,
+    pub pairs: Vec<Pair<'a>>,
+    /// The image picked with `--keep`, which is also the first member.
+    pub keeper: Option<&'a Path>,
+}
+
+/// Writes pairs and groups to stdout in the format chosen with `--format`.
+/// Some formats wrap the whole output, so every pair or group of a run has to
+/// go through the same printer, followed by a call to `finish`.
+pub struct Printer {
+    fmt: Fmt,
+    scores: bool,
+    records: usize,
+    groups: usize,
+    /// The node ID of each image already written to a graph.
+    nodes: HashMap<PathBuf, usize>,
+}
+
+impl Printer {
+    pub fn new(fmt: Fmt) -> Printer {
+        Printer {
+            fmt,
+            scores: false,
+            records: 0,
+            groups: 0,
+            nodes: HashMap::new(),
+        }
+    }
+
+    /// Prints the score of each pair as the first field in the plain formats.
+    /// Structured formats always include scores.
+    pub fn with_scores(mut self) -> Printer {
+        self.scores = true;
+        self
+    }
+
+    pub fn pair(&mut self, a: &Path, b: &Path, score: f64, kind: Match) {
+        match self.fmt {
+            Fmt::Json | Fmt::Jsonl => self.write_record(&Record::Pair {
+                score,
+                kind,
+                images: [ImageRecord::new(a), ImageRecord::new(b)],
+            }),
+            Fmt::Csv | Fmt::Tsv => self.write_rows(&[(a, b, score, kind)], None, None),
+            Fmt::Dot | Fmt::Graphml => {
+                self.write_graph(&[a, b], &[(a, b, score, kind)], None, None)
+            }
+            fmt if self.scores => print_scored(&[a, b], score, fmt),
+            fmt => print_fmt(&[a, b], fmt),
+        }
+    }
+
+    /// Adds pairs whose images ended up in different groups, or in none, to a
+    /// graph, so it holds every match rather than only those within groups.
+    /// Images not yet in the graph are added without a group. Other formats
+    /// only print the pairs within groups.
+    pub fn pairs_between_groups(&mut self, pairs: &[Pair]) {
+        if pairs.is_empty() || !matches!(self.fmt, Fmt::Dot | Fmt::Graphml) {
+            return;
+        }
+        let images: Vec<&Path> = pairs.iter().flat_map(|(a, b, _, _)| [*a, *b]).collect();
+        self.write_graph(&images, pairs, None, None);
+    }
+
+    /// Prints a group along with the pairs that connected its members. Groups
+    /// are numbered from 1 in the order they are printed.
+    pub fn group(&mut self, group: &Group) {
+        self.groups += 1;
+        match self.fmt {
+            Fmt::Json | Fmt::Jsonl => self.write_record(&Record::Group {
+                images: group
+                    .members
+                    .iter()
+                    .map(|path| ImageRecord {
+                        keeper: group.keeper == Some(*path),
+                        ..ImageRecord::new(path)
+                    })
+                    .collect(),
+                pairs: group
+                    .pairs
+                    .iter()
+                    .map(|(a, b, score, kind)| PairRecord {
+                        a: a.to_string_lossy().into_owned(),
+                        b: b.to_string_lossy().into_owned(),
+                        score: *score,
+                        kind: *kind,
+                    })
+                    .collect(),
+            }),
+            Fmt::Csv | Fmt::Tsv => self.write_rows(&group.pairs, Some(self.groups), group.keeper),
+            Fmt::Dot | Fmt::Graphml => self.write_graph(
+                &group.members,
+                &group.pairs,
+                Some(self.groups),
+                group.keeper,
+            ),
+            fmt => print_fmt(&group.members, fmt),
+        }
+    }
+
+    /// JSON output is a single array of records, while JSONL has one record
+    /// per line so it can be read while the search is still running.
+    fn write_record(&mut self, record: &Record) {
+        let mut line = match (self.fmt, self.records) {
+            (Fmt::Json, 0) => b"[\n".to_vec(),
+            (Fmt::Json, _) => b",\n".to_vec(),
+            _ => Vec::new(),
+        };
+        serde_json::to_writer(&mut line, record).expect("Unable to serialize record");
+        if matches!(self.fmt, Fmt::Jsonl) {
+            line.push(b'\n');
+        }
+        write_bytes(&line);
+        self.records += 1;
+    }
+
+    /// CSV and TSV have one row per pair, preceded by a header. CSV rows end
+    /// in CRLF as RFC 4180 prescribes. Each row names the image kept in its
+    /// group, if one was picked, and how the pair matched.
+    fn write_rows(&mut self, pairs: &[Pair], group_id: Option<usize>, keeper: Option<&Path>) {
+        let (delimiter, terminator): (u8, &[u8]) = match self.fmt {
+            Fmt::Tsv => (b'\t', b"\n"),
+            _ => (b',', b"\r\n"),
+        };
+        let push_row = |out: &mut Vec<u8>, fields: &[&[u8]]| {
+            for (i, field) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push(delimiter);
+                }
+                push_field(out, field, delimiter);
+            }
+            out.extend_from_slice(terminator);
+        };
+
+        let mut out = Vec::new();
+        if self.records == 0 {
+            let header: Vec<&[u8]> = TABLE_HEADER.iter().map(|name| name.as_bytes()).collect();
+            push_row(&mut out, &header);
+        }
+
+        // Images show up in several pairs of a group, but only need to be read
+        // once.
+        let mut images: HashMap<&Path, ImageRecord> = HashMap::new();
+        for (a, b, score, kind) in pairs {
+            let mut fields = vec![
+                path_to_bytes(a).to_vec(),
+                path_to_bytes(b).to_vec(),
+                format!("{:.4}", score).into_bytes(),
+                group_id
+                    .map(|id| id.to_string())
+                    .unwrap_or_default()
+                    .into_bytes(),
+            ];
+            for path in [a, b] {
+                let image = images.entry(path).or_insert_with(|| ImageRecord::new(path));
+                let field = |value: Option<String>| value.unwrap_or_default().into_bytes();
+                fields.push(field(image.width.map(|width| width.to_string())));
+                fields.push(field(image.height.map(|height| height.to_string())));
+                fields.push(field(image.size.map(|size| size.to_string())));
+                fields.push(field(image.modified.map(format_time)));
+            }
+            fields.push(keeper.map(path_to_bytes).unwrap_or_default().to_vec());
+            fields.push(kind.to_string().into_bytes());
+            let fields: Vec<&[u8]> = fields.iter().map(|field| field.as_slice()).collect();
+            push_row(&mut out, &fields);
+        }
+
+        write_bytes(&out);
+        self.records += 1;
+    }
+
+    /// Graphs have a node for every image and an edge for every pair, with
+    /// the score as its weight. Each image is written as a node the first time
+    /// it appears, and is labelled with its group if there is one. The image
+    /// kept in a group is marked, and drawn with a double border in DOT.
+    /// Edges say how the pair matched, and all but perceptual matches are drawn
+    /// bold in DOT.
+    fn write_graph(
+        &mut self,
+        images: &[&Path],
+        pairs: &[Pair],
+        group_id: Option<usize>,
+        keeper: Option<&Path>,
+    ) {
+        let out = self.graph(images, pairs, group_id, keeper);
+        write_bytes(out.as_bytes());
+        self.records += 1;
+    }
+
+    fn graph(
+        &mut self,
+        images: &[&Path],
+        pairs: &[Pair],
+        group_id: Option<usize>,
+        keeper: Option<&Path>,
+    ) -> String {
+        let mut out = String::new();
+        if self.records == 0 {
+            match self.fmt {
+                Fmt::Dot => out.push_str("graph similar {\n  node [shape=box];\n"),
+                _ => out.push_str(GRAPHML_HEADER),
+            }
+        }
+
+        // Writing to a String can't fail.
+        for path in images {
+            if self.nodes.contains_key(*path) {
+                continue;
+            }
+            let id = self.nodes.len();
+            self.nodes.insert(path.to_path_buf(), id);
+            let label = path
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default();
+            let full_path = path.to_string_lossy();
+            match self.fmt {
+                Fmt::Dot => {
+                    write!(
+                        out,
+                        "  n{} [label={}, path={}",
+                        id,
+                        dot_string(&label),
+                        dot_string(&full_path)
+                    )
+                    .unwrap();
+                    if let Some(group_id) = group_id {
+                        write!(out, ", group={}", group_id).unwrap();
+                    }
+                    if keeper == Some(*path) {
+                        out.push_str(", keeper=true, peripheries=2");
+                    }
+                    out.push_str("];\n");
+                }
+                _ => {
+                    write!(
+                        out,
+                        "    <node id=\"n{}\"><data key=\"label\">{}</data><data key=\"path\">{}</data>",
+                        id,
+                        escape_html(&label),
+                        escape_html(&full_path)
+                    )
+                    .unwrap();
+                    if let Some(group_id) = group_id {
+                        write!(out, "<data key=\"group\">{}</data>", group_id).unwrap();
+                    }
+                    if keeper == Some(*path) {
+                        out.push_str("<data key=\"keeper\">true</data>");
+                    }
+                    out.push_str("</node>\n");
+                }
+            }
+        }
+
+        for (a, b, score, kind) in pairs {
+            let (a, b) = (self.nodes[*a], self.nodes[*b]);
+            match self.fmt {
+                Fmt::Dot => writeln!(
+                    out,
+                    "  n{} -- n{} [weight={:.4}, label=\"{:.4}\", match={}{}];",
+                    a,
+                    b,
+                    score,
+                    score,
+                    dot_string(&kind.to_string()),
+                    if *kind == Match::Perceptual { "" } else { ", style=bold" }
+                ),
+                _ => writeln!(
+                    out,
+                    "    <edge source=\"n{}\" target=\"n{}\"><data key=\"weight\">{:.4}</data><data key=\"match\">{}</data></edge>",
+                    a, b, score, kind
+                ),
+            }
+            .unwrap();
+        }
+        out
+    }
+
+    pub fn finish(&mut self) {
+        match self.fmt {
+            Fmt::Json if self.records == 0 => write_bytes(b"[]\n"),
+            Fmt::Json => write_bytes(b"\n]\n"),
+            // The header still tells a reader that nothing was found.
+            Fmt::Csv | Fmt::Tsv if self.records == 0 => self.write_rows(&[], None, None),
+            Fmt::Dot | Fmt::Graphml if self.records == 0 => self.write_graph(&[], &[], None, None),
+            _ => (),
+        }
+        match self.fmt {
+            Fmt::Dot => write_bytes(b"}\n"),
+            Fmt::Graphml => write_bytes(b"  </graph>\n</graphml>\n"),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn field(field: &str, delimiter: u8) -> String {
+        let mut row = Vec::new();
+        push_field(&mut row, field.as_bytes(), delimiter);
+        String::from_utf8(row).unwrap()
+    }
+
+    #[test]
+    fn test_push_field() {
+        assert_eq!(field("/photos/a.jpg", b','), "/photos/a.jpg");
+        assert_eq!(field("/photos/a, b.jpg", b','), "\"/photos/a, b.jpg\"");
+        assert_eq!(field("/photos/a, b.jpg", b'\t'), "/photos/a, b.jpg");
+        assert_eq!(field("/photos/a\tb.jpg", b'\t'), "\"/photos/a\tb.jpg\"");
+        assert_eq!(
+            field("/photos/\"a\".jpg", b','),
+            "\"/photos/\"\"a\"\".jpg\""
+        );
+        assert_eq!(
+            field("/photos/new\nline.jpg", b','),
+            "\"/photos/new\nline.jpg\""
+        );
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(escape_html("/photos/a.jpg"), "/photos/a.jpg");
+        assert_eq!(
+            escape_html("/photos/<b>&\"c\"'.jpg"),
+            "/photos/&lt;b&gt;&amp;&quot;c&quot;&#39;.jpg"
+        );
+        assert_eq!(escape_html("/photos/\u{1}.jpg"), "/photos/\u{FFFD}.jpg");
+    }
+
+    #[test]
+    fn test_dot_string() {
+        assert_eq!(dot_string("/photos/a.jpg"), "\"/photos/a.jpg\"");
+        assert_eq!(
+            dot_string("/photos/\"a\"\\b\nc.jpg"),
+            "\"/photos/\\\"a\\\"\\\\b\\nc.jpg\""
+        );
+    }
+
+    #[test]
+    fn test_dot_graph() {
+        let mut printer = Printer::new(Fmt::Dot);
+        let (a, b, c) = (
+            Path::new("/a.png"),
+            Path::new("/b.png"),
+            Path::new("/c.png"),
+        );
+        let pairs = [(a, b, 1.0, Match::Pixel), (a, c, 0.25, Match::Perceptual)];
+        assert_eq!(
+            printer.graph(&[a, b, c], &pairs, Some(0), Some(a)),
+            concat!(
+                "graph similar {\n  node [shape=box];\n",
+                "  n0 [label=\"a.png\", path=\"/a.png\", group=0, keeper=true, peripheries=2];\n",
+                "  n1 [label=\"b.png\", path=\"/b.png\", group=0];\n",
+                "  n2 [label=\"c.png\", path=\"/c.png\", group=0];\n",
+                "  n0 -- n1 [weight=1.0000, label=\"1.0000\", match=\"pixel-identical\", style=bold];\n",
+                "  n0 -- n2 [weight=0.2500, label=\"0.2500\", match=\"perceptual\"];\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00:00");
+        assert_eq!(format_time(1700000000), "2023-11-14 22:13:20");
+        assert_eq!(format_time(1735689599), "2024-12-31 23:59:59");
     }
 }
@@ -0,0 +1,140 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::exit,
+    sync::Mutex,
+};
+
+use rusqlite::Connection;
+
+use crate::{
+    cli::{Cli, IgnoreArgs},
+    database,
+    shared::{content_hash, Hash},
+};
+
+fn ordered(a: Hash, b: Hash) -> (Hash, Hash) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// The pairs of images marked as not being duplicates. Files are only hashed
+/// when there are ignored pairs to check them against, and only once.
+#[derive(Default)]
+pub struct IgnoredPairs {
+    pairs: HashSet<(Hash, Hash)>,
+    hashes: Mutex<HashMap<PathBuf, Option<Hash>>>,
+}
+
+impl IgnoredPairs {
+    pub fn load(conn: &Connection) -> anyhow::Result<IgnoredPairs> {
+        let pairs = database::fetch_ignored(conn)?
+            .into_iter()
+            .filter_map(|(a, b)| Some((a.try_into().ok()?, b.try_into().ok()?)))
+            .collect();
+        Ok(IgnoredPairs {
+            pairs,
+            hashes: Mutex::default(),
+        })
+    }
+
+    fn hash(&self, path: &Path) -> Option<Hash> {
+        if let Some(hash) = self.hashes.lock().expect("Hash cache poisoned").get(path) {
+            return *hash;
+        }
+        // Hashed without holding the lock, so other threads aren't held up.
+        let hash = content_hash(path).ok();
+        self.hashes
+            .lock()
+            .expect("Hash cache poisoned")
+            .insert(path.to_path_buf(), hash);
+        hash
+    }
+
+    pub fn contains(&self, a: &Path, b: &Path) -> bool {
+        if self.pairs.is_empty() {
+            return false;
+        }
+        match (self.hash(a), self.hash(b)) {
+            (Some(a), Some(b)) => self.pairs.contains(&ordered(a, b)),
+            _ => false,
+        }
+    }
+}
+
+/// Marks pairs of images as not being duplicates, or unmarks them.
+pub fn store(conn: &mut Connection, pairs: &[(&Path, &Path)], ignored: bool) -> anyhow::Result<()> {
+    let mut hashes: HashMap<&Path, Hash> = HashMap::new();
+    let mut keys = Vec::new();
+    for (a, b) in pairs {
+        for path in [a, b] {
+            if !hashes.contains_key(path) {
+                let hash =
+                    content_hash(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+                hashes.insert(path, hash);
+            }
+        }
+        keys.push(ordered(hashes[a], hashes[b]));
+    }
+    let keys: Vec<(&[u8], &[u8])> = keys
+        .iter()
+        .map(|(a, b)| (a.as_slice(), b.as_slice()))
+        .collect();
+    database::store_ignored(conn, &keys, ignored)
+}
+
+pub fn main_ignore(cli: &Cli, args: &IgnoreArgs) {
+    let db_path = database::database_path(cli).expect("Unable to figure out database path");
+    let mut conn = database::open(&db_path);
+
+    let mut pairs = Vec::new();
+    for (i, a) in args.images.iter().enumerate() {
+        for b in &args.images[i + 1..] {
+            pairs.push((a.as_path(), b.as_path()));
+        }
+    }
+    if let Err(e) = store(&mut conn, &pairs, !args.remove) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_ignored_pairs() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        database::init(&conn).unwrap();
+        assert!(!IgnoredPairs::load(&conn).unwrap().contains(
+            Path::new("/nonexistent/a.jpg"),
+            Path::new("/nonexistent/b.jpg")
+        ));
+
+        let dir = std::env::temp_dir().join(format!("simagef-ignored-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b, c) = (dir.join("a.jpg"), dir.join("b.jpg"), dir.join("c.jpg"));
+        std::fs::write(&a, b"a").unwrap();
+        std::fs::write(&b, b"b").unwrap();
+        std::fs::write(&c, b"c").unwrap();
+
+        store(&mut conn, &[(&b, &a)], true).unwrap();
+        // Renamed files are still recognized.
+        let renamed = dir.join("renamed.jpg");
+        std::fs::rename(&a, &renamed).unwrap();
+        let ignored = IgnoredPairs::load(&conn).unwrap();
+        assert!(ignored.contains(&renamed, &b));
+        assert!(ignored.contains(&b, &renamed));
+        assert!(!ignored.contains(&b, &c));
+
+        store(&mut conn, &[(&renamed, &b)], false).unwrap();
+        assert_eq!(database::fetch_ignored(&conn).unwrap().len(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -15,8 +15,8 @@ use num::Signed;
 
 pub mod image;
 
-const DEFAULT_CROP: f32 = 0.05;
-const DEFAULT_GRID_SIZE: usize = 10;
+pub(crate) const DEFAULT_CROP: f32 = 0.05;
+pub(crate) const DEFAULT_GRID_SIZE: usize = 10;
 
 /// Produces a 544 signed byte signature for a provided image that's encoded as an array of
 /// conceptually grouped RGBA bytes with the provided width. The result is designed to be compared
@@ -0,0 +1,327 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+use lsh_rs2::prelude::*;
+use rusqlite::Connection;
+
+use crate::{
+    database::{self, StoredIndex},
+    shared::path_from_bytes,
+};
+
+/// The LSH index used to find candidate pairs before computing their cosine
+/// similarity.
+pub type SignatureIndex = LshMem<L2<f32, i8>, f32>;
+
+const N_PROJECTIONS: usize = 5;
+const N_HASH_TABLES: usize = 20;
+const DIM: usize = database::SIGNATURE_LEN;
+const SEED: u64 = 4001;
+
+pub fn get_bucket_width(threshold: u8) -> f32 {
+    if threshold < 10 {
+        140.0
+    } else if threshold < 20 {
+        130.0
+    } else if threshold < 30 {
+        120.0
+    } else if threshold < 40 {
+        110.0
+    } else if threshold < 50 {
+        100.0
+    } else if threshold < 60 {
+        100.0
+    } else if threshold < 70 {
+        80.0
+    } else if threshold < 80 {
+        70.0
+    } else if threshold < 90 {
+        60.0
+    } else if threshold < 95 {
+        30.0
+    } else {
+        25.0
+    }
+}
+
+/// Identifies a stored index, since an index is only valid for the parameters
+/// it was built with and the signatures it was built from.
+fn index_key(threshold: u8) -> String {
+    format!(
+        "l2 width={} projections={} tables={} dim={} seed={} signatures=[{}]",
+        get_bucket_width(threshold),
+        N_PROJECTIONS,
+        N_HASH_TABLES,
+        DIM,
+        SEED,
+        database::signature_algorithm()
+    )
+}
+
+/// Creates an empty index with buckets wide enough for `threshold`.
+pub fn new_index(threshold: u8) -> SignatureIndex {
+    let bucket_width = get_bucket_width(threshold);
+    LshMem::<_, f32>::new(N_PROJECTIONS, N_HASH_TABLES, DIM)
+        .seed(SEED)
+        .only_index()
+        .l2(bucket_width)
+        .expect("Unable to set up LSH")
+}
+
+/// Signatures are stored as bytes but hashed as floats.
+pub fn to_vector(signature: &[i8]) -> Vec<f32> {
+    signature.iter().map(|v| *v as f32).collect()
+}
+
+/// A file for lsh_rs2 to (de)serialize through, since it can't do so in
+/// memory. Removed when dropped.
+struct IndexFile(PathBuf);
+
+impl IndexFile {
+    /// Creates a new file in the temporary directory. Names there are easy to
+    /// guess, so the file is created exclusively rather than writing through
+    /// whatever someone else may have put in its place.
+    fn create() -> io::Result<IndexFile> {
+        let mut attempt = 0;
+        loop {
+            let path = std::env::temp_dir().join(format!(
+                "simagef-index-{}-{}",
+                std::process::id(),
+                attempt
+            ));
+            match File::options().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(IndexFile(path)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for IndexFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+fn dump_index(lsh: &SignatureIndex) -> anyhow::Result<Vec<u8>> {
+    let file = IndexFile::create()?;
+    lsh.dump(&file.0)?;
+    Ok(std::fs::read(&file.0)?)
+}
+
+fn load_index(lsh: &mut SignatureIndex, data: &[u8]) -> anyhow::Result<()> {
+    let file = IndexFile::create()?;
+    std::fs::write(&file.0, data)?;
+    Ok(lsh.load(&file.0)?)
+}
+
+/// An index over every signature in the database, stored in the database
+/// between runs so that only signatures added since the last run have to be
+/// hashed.
+pub struct PersistentIndex {
+    key: String,
+    threshold: u8,
+    lsh: SignatureIndex,
+    /// The path of each id in the index.
+    paths: Vec<PathBuf>,
+    /// The current id of each path. Ids of replaced signatures are left in
+    /// the index, but no longer appear here.
+    current: HashMap<PathBuf, u32>,
+    /// How many entries of `paths` are already stored.
+    saved: usize,
+    last_rowid: i64,
+    last_path: Vec<u8>,
+}
+
+impl PersistentIndex {
+    fn empty(threshold: u8) -> PersistentIndex {
+        PersistentIndex {
+            key: index_key(threshold),
+            threshold,
+            lsh: new_index(threshold),
+            paths: Vec::new(),
+            current: HashMap::new(),
+            saved: 0,
+            last_rowid: 0,
+            last_path: Vec::new(),
+        }
+    }
+
+    /// Loads the stored index for `threshold`, or starts a new one if there is
+    /// none or it no longer matches the signatures table.
+    pub fn open(conn: &Connection, threshold: u8) -> anyhow::Result<PersistentIndex> {
+        let mut index = PersistentIndex::empty(threshold);
+
+        let stored = match database::fetch_index(conn, &index.key)? {
+            Some(stored) => stored,
+            None => return Ok(index),
+        };
+
+        // Deleting the newest rows or vacuuming lets SQLite reuse rowids, which
+        // would make us skip signatures.
+        if stored.last_rowid > 0
+            && database::path_at_rowid(conn, stored.last_rowid)? != Some(stored.last_path.clone())
+        {
+            return Ok(index);
+        }
+
+        load_index(&mut index.lsh, &stored.data)?;
+        index.paths = database::fetch_index_paths(conn, &index.key)?;
+        for (id, path) in index.paths.iter().enumerate() {
+            index.current.insert(path.clone(), id as u32);
+        }
+        index.saved = index.paths.len();
+        index.last_rowid = stored.last_rowid;
+        index.last_path = stored.last_path;
+
+        Ok(index)
+    }
+
+    /// Hashes the signatures added to the database since the index was last
+    /// synced. Returns how many were added.
+    pub fn sync(&mut self, conn: &Connection) -> anyhow::Result<usize> {
+        // Start over once replaced signatures make up most of the index.
+        if self.paths.len() > 2 * self.current.len() + 1024 {
+            *self = PersistentIndex::empty(self.threshold);
+        }
+
+        let mut added = 0;
+        let mut result = Ok(());
+        database::fetch_after(conn, self.last_rowid, |rowid, path_bytes, signature| {
+            if result.is_err() {
+                return;
+            }
+            match self.lsh.store_vec(&to_vector(&signature)) {
+                Ok(id) => {
+                    let path = path_from_bytes(path_bytes.clone());
+                    self.paths.push(path.clone());
+                    self.current.insert(path, id);
+                    self.last_rowid = rowid;
+                    self.last_path = path_bytes;
+                    added += 1;
+                }
+                Err(e) => result = Err(e),
+            }
+        })?;
+        result?;
+
+        Ok(added)
+    }
+
+    /// The paths of stored signatures that share a bucket with `signature`.
+    pub fn candidates(&self, signature: &[i8]) -> anyhow::Result<Vec<&Path>> {
+        let ids = self.lsh.query_bucket_ids(&to_vector(signature))?;
+        Ok(ids
+            .into_iter()
+            .map(|id| (id, &self.paths[id as usize]))
+            .filter(|(id, path)| self.current.get(*path) == Some(id))
+            .map(|(_, path)| path.as_path())
+            .collect())
+    }
+
+    /// Whether the signature stored under `path` is in the index.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.current.contains_key(path)
+    }
+
+    /// Whether the index has entries that aren't stored yet.
+    pub fn is_changed(&self) -> bool {
+        self.saved < self.paths.len()
+    }
+
+    /// Writes the index and any new entries to the database.
+    pub fn save(&mut self, conn: &mut Connection) -> anyhow::Result<()> {
+        let stored = StoredIndex {
+            last_rowid: self.last_rowid,
+            last_path: self.last_path.clone(),
+            data: dump_index(&self.lsh)?,
+        };
+        database::store_index(conn, &self.key, &stored, self.saved, &self.paths)?;
+        self.saved = self.paths.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// A signature with values between -2 and 2 like real ones, far from
+    /// those with other seeds.
+    fn signature(seed: u64) -> Vec<i8> {
+        let mut state = seed;
+        (0..DIM)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                ((state >> 33) % 5) as i8 - 2
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_persistent_index() {
+        let dir = std::env::temp_dir().join(format!("simagef-index-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("db");
+        let mut conn = Connection::open(&db_path).unwrap();
+        database::init(&conn).unwrap();
+        let stat = std::fs::metadata(&db_path).unwrap();
+        let store = |conn: &Connection, path: &str, seed: u64| {
+            database::insert(conn, Path::new(path), &stat, None, None, &signature(seed)).unwrap();
+        };
+        store(&conn, "/a.png", 1);
+        store(&conn, "/b.png", 2);
+
+        let mut index = PersistentIndex::open(&conn, 90).unwrap();
+        assert_eq!(index.sync(&conn).unwrap(), 2);
+        assert!(index.contains(Path::new("/a.png")));
+        assert_eq!(
+            index.candidates(&signature(1)).unwrap(),
+            vec![Path::new("/a.png")]
+        );
+        assert!(index.is_changed());
+        index.save(&mut conn).unwrap();
+        assert!(!index.is_changed());
+
+        // Only signatures added since are hashed.
+        store(&conn, "/c.png", 3);
+        let mut index = PersistentIndex::open(&conn, 90).unwrap();
+        assert_eq!(index.sync(&conn).unwrap(), 1);
+        assert_eq!(
+            index.candidates(&signature(2)).unwrap(),
+            vec![Path::new("/b.png")]
+        );
+        assert_eq!(
+            index.candidates(&signature(3)).unwrap(),
+            vec![Path::new("/c.png")]
+        );
+        index.save(&mut conn).unwrap();
+
+        // Pruning the newest row lets SQLite give its rowid to the next one,
+        // which the stored index mustn't take for the row it already has.
+        let newest = database::fetch_entries(&conn)
+            .unwrap()
+            .into_iter()
+            .find(|entry| entry.path == Path::new("/c.png"))
+            .unwrap();
+        database::delete_entries(&mut conn, &[newest.id]).unwrap();
+        store(&conn, "/d.png", 4);
+        let mut index = PersistentIndex::open(&conn, 90).unwrap();
+        assert_eq!(index.sync(&conn).unwrap(), 3);
+        assert!(index.candidates(&signature(3)).unwrap().is_empty());
+        assert_eq!(
+            index.candidates(&signature(4)).unwrap(),
+            vec![Path::new("/d.png")]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    io::BufRead,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use crossbeam::channel::Sender;
+use ignore::WalkBuilder;
+
+use crate::{
+    cli::Cli,
+    filter::{InputFilter, IGNORE_FILENAME},
+    shared::path_from_bytes,
+};
+
+/// How filenames are read from stdin and how directories are walked.
+#[derive(Debug, Clone)]
+pub struct InputOptions {
+    pub max_depth: Option<usize>,
+    pub follow_symlinks: bool,
+    pub one_file_system: bool,
+    pub hidden: bool,
+    pub ignore_files: Vec<PathBuf>,
+    pub null_input: bool,
+}
+
+impl InputOptions {
+    pub fn from_cli(cli: &Cli) -> InputOptions {
+        InputOptions {
+            max_depth: cli.max_depth,
+            follow_symlinks: cli.follow_symlinks,
+            one_file_system: cli.one_file_system,
+            hidden: cli.hidden,
+            ignore_files: cli.ignore_file.clone(),
+            null_input: cli.null_input,
+        }
+    }
+}
+
+/// Which of the sets given on the command line a file belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputSet {
+    /// Every file is compared against every other file.
+    All,
+    /// Files given with `--reference`, only compared against queries.
+    Reference,
+    /// Files given with `--query`, only compared against references.
+    Query,
+}
+
+impl InputSet {
+    /// Whether a file from this set should be compared with one from `other`.
+    pub fn compares_with(self, other: InputSet) -> bool {
+        self == InputSet::All || self != other
+    }
+}
+
+/// A file to compare along with the set it came from.
+#[derive(Debug)]
+pub struct InputFile {
+    pub path: PathBuf,
+    pub set: InputSet,
+}
+
+/// The files and directories given on the command line, tagged with their
+/// set. A dash stands for filenames read from stdin.
+pub fn input_files(cli: &Cli) -> Vec<InputFile> {
+    let tag = |paths: &[PathBuf], set: InputSet| {
+        paths
+            .iter()
+            .map(|path| InputFile {
+                path: path.clone(),
+                set,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    if cli.reference.is_empty() {
+        tag(&cli.files, InputSet::All)
+    } else {
+        let mut files = tag(&cli.reference, InputSet::Reference);
+        files.extend(tag(&cli.query, InputSet::Query));
+        files
+    }
+}
+
+/// Sends filenames to the signature threads and keeps the progress bar total
+/// up to date while doing so.
+struct FilenameSender {
+    filename_tx: Sender<InputFile>,
+    calc_total_tx: Sender<u64>,
+    filter: Arc<InputFilter>,
+    pending: u64,
+}
+
+impl FilenameSender {
+    fn send(&mut self, filename: PathBuf, set: InputSet) {
+        if !self.filter.accepts_file(&filename) {
+            return;
+        }
+        self.filename_tx
+            .send(InputFile {
+                path: filename,
+                set,
+            })
+            .expect("Unable to send filename to channel");
+        self.pending += 1;
+        if self.pending >= 100 && self.calc_total_tx.try_send(self.pending).is_ok() {
+            self.pending = 0;
+        }
+    }
+
+    /// Sends a file as is, or every file below it if it is a directory.
+    fn send_path(&mut self, filename: PathBuf, set: InputSet, options: &InputOptions) {
+        if filename.is_dir() {
+            self.walk(&filename, set, options);
+        } else {
+            self.send(filename, set);
+        }
+    }
+
+    fn walk(&mut self, dir: &Path, set: InputSet, options: &InputOptions) {
+        let mut builder = WalkBuilder::new(dir);
+        builder
+            .standard_filters(false)
+            .hidden(!options.hidden)
+            .max_depth(options.max_depth)
+            .follow_links(options.follow_symlinks)
+            .same_file_system(options.one_file_system)
+            .add_custom_ignore_filename(IGNORE_FILENAME);
+        // Problems with these were reported when the filter read them.
+        for ignore_file in &options.ignore_files {
+            builder.add_ignore(ignore_file);
+        }
+        let filter = self.filter.clone();
+        builder.filter_entry(move |entry| {
+            entry.depth() == 0
+                || !entry.file_type().is_some_and(|t| t.is_dir())
+                || filter.accepts_dir(entry.path())
+        });
+        let walker = builder.build();
+
+        for entry in walker {
+            match entry {
+                Ok(entry) => {
+                    if !entry.file_type().is_some_and(|t| t.is_file()) {
+                        continue;
+                    }
+                    self.send(entry.into_path(), set);
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    }
+
+    fn finish(self) {
+        self.calc_total_tx.send(self.pending).ok();
+    }
+}
+
+/// Strips the delimiter from a record read from stdin, along with the carriage
+/// return of a CRLF line ending. The last record may lack a delimiter.
+fn trim_record(record: &[u8], delimiter: u8) -> &[u8] {
+    let record = record.strip_suffix(&[delimiter]).unwrap_or(record);
+    if delimiter == b'\n' {
+        record.strip_suffix(b"\r").unwrap_or(record)
+    } else {
+        record
+    }
+}
+
+/// Feeds the files and directories given on the command line to `filename_tx`
+/// from a separate thread, followed by filenames read from stdin if one of the
+/// arguments is a dash. Filenames from stdin belong to the set of the dash.
+/// Directories are walked as they are encountered, so the signature threads
+/// can start working before the walk is finished. Files rejected by `filter`
+/// are skipped silently.
+pub fn read_inputs(
+    files: Vec<InputFile>,
+    options: InputOptions,
+    filter: InputFilter,
+    filename_tx: Sender<InputFile>,
+    calc_total_tx: Sender<u64>,
+) {
+    thread::spawn(move || {
+        let mut sender = FilenameSender {
+            filename_tx,
+            calc_total_tx,
+            filter: Arc::new(filter),
+            pending: 0,
+        };
+        let mut dash_set = None;
+
+        for file in files {
+            if file.path.as_os_str() == "-" {
+                dash_set = Some(file.set);
+            } else {
+                sender.send_path(file.path, file.set, &options);
+            }
+        }
+
+        if let Some(set) = dash_set {
+            let delimiter = if options.null_input { b'\0' } else { b'\n' };
+            let mut stdin_lock = std::io::stdin().lock();
+            let mut buf = Vec::new();
+            loop {
+                match stdin_lock.read_until(delimiter, &mut buf) {
+                    Ok(len) => {
+                        if len == 0 {
+                            break;
+                        }
+                        let record = trim_record(&buf, delimiter);
+                        if !record.is_empty() {
+                            sender.send_path(path_from_bytes(record.to_vec()), set, &options);
+                        }
+                    }
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        break;
+                    }
+                }
+                buf.clear();
+            }
+        }
+
+        sender.finish();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_trim_record() {
+        assert_eq!(trim_record(b"a.png\n", b'\n'), b"a.png");
+        assert_eq!(trim_record(b"a.png\r\n", b'\n'), b"a.png");
+        assert_eq!(trim_record(b"a.png", b'\n'), b"a.png");
+        assert_eq!(trim_record(b"\n", b'\n'), b"");
+        assert_eq!(trim_record(b"new\nline.png\0", b'\0'), b"new\nline.png");
+        assert_eq!(trim_record(b"a.png\r\0", b'\0'), b"a.png\r");
+        assert_eq!(trim_record(b"a.png", b'\0'), b"a.png");
+    }
+
+    #[test]
+    fn test_compares_with() {
+        assert!(InputSet::All.compares_with(InputSet::All));
+        assert!(InputSet::Query.compares_with(InputSet::Reference));
+        assert!(InputSet::Reference.compares_with(InputSet::Query));
+        assert!(!InputSet::Query.compares_with(InputSet::Query));
+        assert!(!InputSet::Reference.compares_with(InputSet::Reference));
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use image::ImageFormat;
+
+use crate::{cli::Keep, formatting::Group, open_image::image_dimensions, sniff::sniff_format};
+
+/// What the rules know about an image. Only the details some rule needs are
+/// read, and details that can't be read are left out.
+#[derive(Default)]
+struct Facts {
+    pixels: Option<u64>,
+    size: Option<u64>,
+    modified: Option<SystemTime>,
+    format: Option<ImageFormat>,
+}
+
+impl Facts {
+    fn new(path: &Path, rules: &[Keep]) -> Facts {
+        let needs = |wanted: fn(&Keep) -> bool| rules.iter().any(wanted);
+        let stat = if needs(|rule| matches!(rule, Keep::Largest | Keep::Oldest | Keep::Newest)) {
+            std::fs::metadata(path).ok()
+        } else {
+            None
+        };
+        Facts {
+            pixels: if needs(|rule| matches!(rule, Keep::Resolution)) {
+                image_dimensions(path)
+                    .ok()
+                    .map(|(width, height)| width as u64 * height as u64)
+            } else {
+                None
+            },
+            size: stat.as_ref().map(|stat| stat.len()),
+            modified: stat.and_then(|stat| stat.modified().ok()),
+            format: if needs(|rule| matches!(rule, Keep::Format(_))) {
+                sniff_format(path).ok().flatten()
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Orders present values before missing ones, lowest first.
+fn lowest<T: Ord>(a: Option<T>, b: Option<T>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Orders present values before missing ones, highest first.
+fn highest<T: Ord>(a: Option<T>, b: Option<T>) -> Ordering {
+    b.cmp(&a)
+}
+
+/// Makes a path comparable with others given differently, relative or through
+/// a symlink. Paths that can't be resolved are only made absolute.
+fn normalize(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Orders two images by a rule, the one to keep first.
+fn compare(rule: &Keep, (path_a, a): (&Path, &Facts), (path_b, b): (&Path, &Facts)) -> Ordering {
+    match rule {
+        Keep::Resolution => highest(a.pixels, b.pixels),
+        Keep::Largest => highest(a.size, b.size),
+        Keep::Oldest => lowest(a.modified, b.modified),
+        Keep::Newest => highest(a.modified, b.modified),
+        Keep::Format(formats) => {
+            let rank = |format: Option<ImageFormat>| {
+                format.and_then(|format| formats.iter().position(|wanted| *wanted == format))
+            };
+            lowest(rank(a.format), rank(b.format))
+        }
+        Keep::ShortestPath => path_a.as_os_str().len().cmp(&path_b.as_os_str().len()),
+        Keep::Prefix(prefix) => {
+            let prefix = normalize(prefix);
+            let inside = |path: &Path| normalize(path).starts_with(&prefix);
+            inside(path_b).cmp(&inside(path_a))
+        }
+    }
+}
+
+/// The index of the image to keep. When the rules can't tell images apart,
+/// the one listed first is kept.
+fn best(rules: &[Keep], members: &[&Path], facts: &[Facts]) -> usize {
+    (0..members.len())
+        .min_by(|a, b| {
+            rules.iter().fold(Ordering::Equal, |ordering, rule| {
+                ordering.then_with(|| {
+                    compare(rule, (members[*a], &facts[*a]), (members[*b], &facts[*b]))
+                })
+            })
+        })
+        .unwrap_or(0)
+}
+
+/// Picks the image to keep in each group with the `--keep` rules, and moves it
+/// to the front of the group.
+pub fn choose_keepers(groups: &mut [Group], rules: &[Keep]) {
+    if rules.is_empty() {
+        return;
+    }
+    for group in groups {
+        let facts: Vec<Facts> = group
+            .members
+            .iter()
+            .map(|path| Facts::new(path, rules))
+            .collect();
+        let keeper = group.members.remove(best(rules, &group.members, &facts));
+        group.members.insert(0, keeper);
+        group.keeper = Some(keeper);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn rules(value: &str) -> Vec<Keep> {
+        value.split(',').map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            rules("resolution,format:png>webp>jpg,prefix:/photos"),
+            vec![
+                Keep::Resolution,
+                Keep::Format(vec![ImageFormat::Png, ImageFormat::WebP, ImageFormat::Jpeg]),
+                Keep::Prefix("/photos".into()),
+            ]
+        );
+        assert!("format:png>doc".parse::<Keep>().is_err());
+        assert!("biggest".parse::<Keep>().is_err());
+    }
+
+    #[test]
+    fn test_best() {
+        let members = [
+            Path::new("/downloads/a.jpg"),
+            Path::new("/photos/b.jpg"),
+            Path::new("/photos/archive/c.png"),
+        ];
+        let facts = [
+            Facts {
+                pixels: Some(12_000_000),
+                size: Some(3_000_000),
+                format: Some(ImageFormat::Jpeg),
+                ..Default::default()
+            },
+            Facts {
+                pixels: Some(12_000_000),
+                size: None,
+                format: Some(ImageFormat::Jpeg),
+                ..Default::default()
+            },
+            Facts {
+                pixels: Some(2_000_000),
+                size: Some(5_000_000),
+                format: Some(ImageFormat::Png),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(best(&[], &members, &facts), 0);
+        assert_eq!(best(&rules("resolution"), &members, &facts), 0);
+        assert_eq!(best(&rules("largest"), &members, &facts), 2);
+        assert_eq!(best(&rules("format:png>jpg"), &members, &facts), 2);
+        assert_eq!(
+            best(&rules("prefix:/photos,resolution"), &members, &facts),
+            1
+        );
+        assert_eq!(
+            best(&rules("resolution,shortest-path"), &members, &facts),
+            1
+        );
+    }
+
+    #[test]
+    fn test_prefix_relative_paths() {
+        let cwd = std::env::current_dir().unwrap();
+        let facts = [Facts::default(), Facts::default()];
+        let members = [Path::new("downloads/a.jpg"), Path::new("photos/b.jpg")];
+        let rule = [Keep::Prefix(cwd.join("photos"))];
+        assert_eq!(best(&rule, &members, &facts), 1);
+
+        let b = cwd.join("photos/b.jpg");
+        let members = [Path::new("/downloads/a.jpg"), &b];
+        assert_eq!(best(&rules("prefix:photos"), &members, &facts), 1);
+    }
+}
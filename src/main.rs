@@ -1,23 +1,39 @@
+mod action;
 mod cli;
+mod cluster;
+mod contact_sheet;
 mod database;
+mod exact;
+mod filter;
 mod formatting;
+mod ignored;
+mod index;
+mod input;
+mod keep;
 #[cfg(feature = "pixel")]
 mod main_image;
+mod maintenance;
 mod open_image;
+mod query;
+mod report;
+mod review;
+mod script;
 mod shared;
+mod sniff;
+mod watch;
 
 use core::fmt;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     error::Error,
-    io::BufRead,
-    path::PathBuf,
-    process::{exit, Command},
+    path::{Path, PathBuf},
+    process::exit,
+    sync::Arc,
     thread::{self, JoinHandle},
 };
 
 use clap::Parser;
-use cli::Cli;
+use cli::{Cli, ContentHash};
 use crossbeam::{
     channel::{never, Receiver, Sender},
     select,
@@ -28,12 +44,40 @@ use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use rusqlite::Connection;
 
 use crate::{
-    cli::Fmt, database::InsertionMessage, formatting::print_fmt, open_image::open_image_path, shared::get_executable,
+    database::InsertionMessage,
+    filter::InputFilter,
+    formatting::{Match, Printer},
+    ignored::IgnoredPairs,
+    index::{new_index, to_vector, PersistentIndex},
+    input::{input_files, read_inputs, InputFile, InputOptions, InputSet},
+    open_image::{decode_image_bytes, decode_image_path, pixel_hash},
+    shared::{
+        arrange_groups, get_executable, pairs_between_groups, print_groups_and_exec,
+        print_pair_and_exec, Hash, IndexPair,
+    },
+    sniff::SniffStats,
 };
 
 struct SignatureToCompare {
-    path: String,
+    path: PathBuf,
+    /// The path with links resolved, so the same file given under two paths
+    /// isn't taken for a match of itself.
+    canonical: PathBuf,
+    set: InputSet,
     signature: Vec<i8>,
+    /// Only set with `--pixel-identical`.
+    pixel_hash: Option<Hash>,
+    /// Whether the signature is the one stored in the database under
+    /// `canonical`.
+    unchanged: bool,
+}
+
+impl SignatureToCompare {
+    /// Whether this image should be compared with `other`, which has to come
+    /// from a set this one is compared with and be a different file.
+    fn compares_with(&self, other: &SignatureToCompare) -> bool {
+        self.set.compares_with(other.set) && self.canonical != other.canonical
+    }
 }
 
 struct CompareTask {
@@ -41,110 +85,61 @@ struct CompareTask {
     pub index2: (usize, &'static SignatureToCompare),
 }
 
+#[derive(Clone, Copy)]
 struct Pairing {
     pub index1: (usize, &'static SignatureToCompare),
     pub index2: (usize, &'static SignatureToCompare),
     pub score: f64,
+    pub kind: Match,
 }
 
-fn make_groups<P>(pairs: P) -> Vec<Vec<usize>>
-where
-    P: IntoIterator<Item = Pairing>,
-{
-    let mut graph: HashMap<usize, Vec<usize>> = HashMap::new();
-
-    // Build the graph
-    for pair in pairs.into_iter() {
-        // let (node1, node2) = *pair;
-        let node1 = pair.index1.0;
-        let node2 = pair.index2.0;
-        graph.entry(node1).or_default().push(node2);
-        graph.entry(node2).or_default().push(node1);
-    }
-
-    let mut visited: HashSet<usize> = HashSet::new();
-    let mut groups: Vec<Vec<usize>> = Vec::new();
-
-    // Perform DFS to find connected components
-    fn dfs(
-        node: usize,
-        graph: &HashMap<usize, Vec<usize>>,
-        visited: &mut HashSet<usize>,
-        mut group: Vec<usize>,
-    ) -> Vec<usize> {
-        visited.insert(node);
-        group.push(node);
-        if let Some(neighbors) = graph.get(&node) {
-            for neighbor in neighbors.iter() {
-                if !visited.contains(neighbor) {
-                    group = dfs(*neighbor, graph, visited, group);
-                }
+impl Pairing {
+    /// Puts the query image first when comparing a query set against a
+    /// reference set.
+    fn query_first(self) -> Pairing {
+        if self.index2.1.set == InputSet::Query {
+            Pairing {
+                index1: self.index2,
+                index2: self.index1,
+                ..self
             }
-        }
-        group
-    }
-
-    for node in graph.keys() {
-        if !visited.contains(node) {
-            let mut group: Vec<usize> = Vec::new();
-            group = dfs(*node, &graph, &mut visited, group);
-            groups.push(group);
+        } else {
+            self
         }
     }
-
-    groups
 }
 
-fn make_groups_and_exec<P>(
-    name_map: &[String],
-    pairings: P,
-    executable: &Option<(&str, Vec<&str>)>,
-    fmt: Fmt,
-) where
+/// Groups each query image with the reference images it matched, best match
+/// first. Reference images may appear in more than one group.
+fn make_query_groups<P>(pairs: P) -> Vec<Vec<usize>>
+where
     P: IntoIterator<Item = Pairing>,
 {
-    let groups = make_groups(pairings);
-    for group in groups {
-        let name_group: Vec<&str> = group
-            .iter()
-            .map(|index| name_map[*index].as_ref())
-            .collect();
-        print_fmt(&name_group, fmt);
-        #[cfg(not(feature = "no-exec"))]
-        if let Some((program, args)) = &executable {
-            Command::new(program)
-                .args(args)
-                .args(name_group)
-                .output()
-                .expect("Unable to run program provided");
+    let mut matches: HashMap<usize, Vec<(usize, f64)>> = HashMap::new();
+
+    for pair in pairs.into_iter() {
+        if !pair.index1.1.compares_with(pair.index2.1) {
+            continue;
         }
+        let pair = pair.query_first();
+        matches
+            .entry(pair.index1.0)
+            .or_default()
+            .push((pair.index2.0, pair.score));
     }
-}
 
-fn get_bucket_width(threshold: u8) -> f32 {
-    if threshold < 10 {
-        140.0
-    } else if threshold < 20 {
-        130.0
-    } else if threshold < 30 {
-        120.0
-    } else if threshold < 40 {
-        110.0
-    } else if threshold < 50 {
-        100.0
-    } else if threshold < 60 {
-        100.0
-    } else if threshold < 70 {
-        80.0
-    } else if threshold < 80 {
-        70.0
-    } else if threshold < 90 {
-        60.0
-    } else if threshold < 95 {
-        30.0
-    } else {
-        25.0
-    }
+    let mut matches: Vec<(usize, Vec<(usize, f64)>)> = matches.into_iter().collect();
+    matches.sort_by_key(|(query, _)| *query);
+
+    matches
+        .into_iter()
+        .map(|(query, mut references)| {
+            references.sort_by(|a, b| b.1.total_cmp(&a.1));
+            std::iter::once(query)
+                .chain(references.into_iter().map(|(reference, _)| reference))
+                .collect()
+        })
+        .collect()
 }
 
 #[cfg(feature = "instrumentation")]
@@ -203,47 +198,15 @@ fn progress_bar_loop(calc_total_rx: Receiver<u64>, calc_count_rx: Receiver<u64>)
 const FILENAME_CHANNEL_BOUND: usize = 65535;
 const CHANNEL_BOUND: usize = 2048;
 
-fn read_filenames(filename_tx: Sender<String>, dash_mode: bool, calc_total_tx: Sender<u64>) {
-    if dash_mode {
-        thread::spawn(move || {
-            let mut total = 0;
-            let mut stdin_lock = std::io::stdin().lock();
-            let mut buf = String::new();
-            loop {
-                match stdin_lock.read_line(&mut buf) {
-                    Ok(len) => {
-                        if len == 0 {
-                            break;
-                        }
-                        let slice = buf[0..(len - 1)].to_owned();
-                        filename_tx
-                            .send(slice)
-                            .expect("Unable to send filename to channel");
-                        total += 1;
-                    }
-                    Err(err) => {
-                        eprintln!("{}", err);
-                        break;
-                    }
-                }
-                buf.clear();
-            }
-            calc_total_tx.try_send(total).ok();
-        });
-    }
-}
-
 #[derive(Debug)]
 enum SigFetchError {
-    PathConversionError(&'static str),
+    NotAnImage,
 }
 
 impl fmt::Display for SigFetchError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            SigFetchError::PathConversionError(error) => {
-                write!(f, "Error converting file path to string: {}", error)
-            }
+            SigFetchError::NotAnImage => f.write_str("Not an image"),
         }
     }
 }
@@ -277,49 +240,158 @@ fn spawn_insertion_thread(
     Ok(t)
 }
 
+/// What `fetch_signature` needs from the command line.
+#[derive(Clone, Copy)]
+pub struct FetchOptions {
+    pub content_hash: ContentHash,
+    /// Decode images whose stored signature has no pixel hash yet.
+    pub pixel_hash: bool,
+}
+
+impl FetchOptions {
+    pub fn from_cli(cli: &Cli) -> FetchOptions {
+        FetchOptions {
+            content_hash: cli.content_hash,
+            pixel_hash: cli.pixel_identical,
+        }
+    }
+}
+
+/// A signature from `fetch_signature`.
+pub struct Fetched {
+    pub signature: Vec<i8>,
+    pub pixel_hash: Option<Hash>,
+    /// Whether this is the signature already stored under the file's path,
+    /// and so already in any index over the database.
+    pub unchanged: bool,
+}
+
+/// Looks up the signature of an image in the database, or computes it and sends
+/// it to be stored, along with the hash of its pixels if there is one. Unless
+/// `--content-hash` says otherwise, files that don't match what's stored under
+/// their path are hashed, so the signature of a moved or touched file is found
+/// by its content instead of decoding it again.
 fn fetch_signature(
-    filename: &str,
+    filename: &Path,
     db_conn: &Option<Connection>,
-    insert_tx: &Sender<InsertionMessage>,
-) -> anyhow::Result<Vec<i8>> {
+    insert_tx: Option<&Sender<InsertionMessage>>,
+    sniff_stats: &SniffStats,
+    options: FetchOptions,
+) -> anyhow::Result<Fetched> {
     let filename = std::fs::canonicalize(filename)?;
+    // Only files that have to be decoded are sniffed, so a rescan of files
+    // that are all stored already doesn't open each of them.
+    let decode = |bytes: Option<&[u8]>| -> anyhow::Result<(Vec<i8>, Hash)> {
+        let format = sniff_stats
+            .check(&filename)?
+            .ok_or(SigFetchError::NotAnImage)?;
+        let image = match bytes {
+            Some(bytes) => decode_image_bytes(bytes, format)?,
+            None => decode_image_path(&filename, format)?,
+        };
+        let pixel_hash = pixel_hash(&image);
+        Ok((get_image_signature(image.into_rgba8()), pixel_hash))
+    };
 
-    match db_conn {
-        Some(conn) => {
-            let filename_s = filename.to_str().ok_or(SigFetchError::PathConversionError(
-                "Unable to convert file path",
-            ))?;
-            let stat = std::fs::metadata(&filename)?;
-            let signature = database::fetch(conn, filename_s, &stat)?;
-            match signature {
-                Some(signature) => Ok(signature.signature),
-                None => {
-                    let image = open_image_path(&filename)?;
-                    let signature = get_image_signature(image);
-                    insert_tx
-                        .send(InsertionMessage {
-                            filename_s: filename_s.to_string(),
-                            stat,
-                            signature: signature.clone(),
-                        })
-                        .expect("Unable to send InsertionMessage");
-                    Ok(signature)
-                }
+    let conn = match db_conn {
+        Some(conn) => conn,
+        None => {
+            let (signature, pixel_hash) = decode(None)?;
+            return Ok(Fetched {
+                signature,
+                pixel_hash: Some(pixel_hash),
+                unchanged: false,
+            });
+        }
+    };
+
+    let stat = std::fs::metadata(&filename)?;
+    let stored = database::fetch_stored(conn, &filename)?;
+    let current = stored
+        .as_ref()
+        .is_some_and(|stored| stored.is_current(&stat));
+    let store = |hash: Option<Hash>, signature: Option<&Vec<i8>>, pixel_hash: Option<Hash>| {
+        if let Some(insert_tx) = insert_tx {
+            insert_tx
+                .send(InsertionMessage {
+                    filename: filename.clone(),
+                    stat: stat.clone(),
+                    hash,
+                    signature: signature.cloned(),
+                    pixel_hash,
+                })
+                .expect("Unable to send InsertionMessage");
+        }
+    };
+
+    let hashed = match options.content_hash {
+        ContentHash::Never => false,
+        // Signatures stored by older versions have no hash, which is added the
+        // first time the file is seen so it can be found after it's moved.
+        ContentHash::Fallback => !current || stored.as_ref().is_some_and(|s| s.hash.is_none()),
+        ContentHash::Always => true,
+    };
+
+    // The file is read once, for both the hash and the decoder.
+    let bytes = if hashed {
+        Some(std::fs::read(&filename)?)
+    } else {
+        None
+    };
+    let mut hash = bytes.as_ref().map(|bytes| *blake3::hash(bytes).as_bytes());
+
+    // A stored signature of the file, and whether it's stored under this path.
+    let cached = match (stored, hash) {
+        (stored, None) => stored.filter(|_| current).map(|stored| (stored, true)),
+        (Some(stored), Some(hash))
+            if stored.hash == Some(hash)
+                || (stored.hash.is_none()
+                    && current
+                    && options.content_hash == ContentHash::Fallback) =>
+        {
+            Some((stored, true))
+        }
+        (_, Some(hash)) => {
+            let size = bytes.as_ref().map_or(0, |bytes| bytes.len() as u64);
+            database::fetch_by_content(conn, size, &hash)?.map(|moved| (moved, false))
+        }
+    };
+    if let Some((cached, own)) = cached {
+        if !options.pixel_hash || cached.pixel_hash.is_some() {
+            if !own {
+                store(hash, Some(&cached.signature), cached.pixel_hash);
+            } else if hash.is_some() && (!current || cached.hash.is_none()) {
+                store(hash, None, None);
             }
+            return Ok(Fetched {
+                signature: cached.signature,
+                pixel_hash: cached.pixel_hash,
+                unchanged: own,
+            });
         }
-        None => {
-            let image = open_image_path(&filename)?;
-            Ok(get_image_signature(image))
+        // Decoded again for the pixel hash, keeping the hash already stored.
+        if own {
+            hash = hash.or(cached.hash);
         }
     }
+
+    let (signature, pixel_hash) = decode(bytes.as_deref())?;
+    store(hash, Some(&signature), Some(pixel_hash));
+    Ok(Fetched {
+        signature,
+        pixel_hash: Some(pixel_hash),
+        unchanged: false,
+    })
 }
 
 fn spawn_signature_threads(
-    filename_rx: Receiver<String>,
+    filename_rx: Receiver<InputFile>,
     img_tx: Sender<&'static SignatureToCompare>,
     calc_count_tx: Sender<u64>,
     db_path: Option<PathBuf>,
     insert_tx: Sender<InsertionMessage>,
+    sniff_stats: Arc<SniffStats>,
+    options: FetchOptions,
 ) {
     let cpu_count = num_cpus::get();
 
@@ -329,17 +401,28 @@ fn spawn_signature_threads(
         let calc_count_tx = calc_count_tx.clone();
         let db_path = db_path.clone();
         let insert_tx = insert_tx.clone();
+        let sniff_stats = sniff_stats.clone();
         thread::spawn(move || {
             let db_conn = db_path.as_ref().map(|db_path| {
                 Connection::open(db_path).expect("Unable to open database connection")
             });
             let mut total = 0;
-            while let Ok(filename) = filename_rx.recv() {
-                match fetch_signature(&filename, &db_conn, &insert_tx) {
-                    Ok(signature) => {
+            while let Ok(InputFile {
+                path: filename,
+                set,
+            }) = filename_rx.recv()
+            {
+                match fetch_signature(&filename, &db_conn, Some(&insert_tx), &sniff_stats, options)
+                {
+                    Ok(fetched) => {
                         let stc = SignatureToCompare {
+                            canonical: std::fs::canonicalize(&filename)
+                                .unwrap_or_else(|_| filename.clone()),
                             path: filename,
-                            signature,
+                            set,
+                            signature: fetched.signature,
+                            pixel_hash: fetched.pixel_hash.filter(|_| options.pixel_hash),
+                            unchanged: fetched.unchanged,
                         };
                         let stc = Box::from(stc);
                         let stc = Box::leak(stc);
@@ -352,7 +435,12 @@ fn spawn_signature_threads(
                             total = 0;
                         }
                     }
-                    Err(e) => eprintln!("{}: {}", filename, e),
+                    Err(e) => {
+                        // Non-images are only counted in the summary.
+                        if !matches!(e.downcast_ref(), Some(SigFetchError::NotAnImage)) {
+                            eprintln!("{}: {}", filename.display(), e);
+                        }
+                    }
                 }
             }
             calc_count_tx.send(total).ok();
@@ -360,25 +448,37 @@ fn spawn_signature_threads(
     }
 }
 
-fn spawn_cosine_threads(threshold: f64, task_rx: Receiver<CompareTask>, pair_tx: Sender<Pairing>) {
+fn spawn_cosine_threads(
+    threshold: f64,
+    task_rx: Receiver<CompareTask>,
+    pair_tx: Sender<Pairing>,
+    ignored: Arc<IgnoredPairs>,
+) {
     let cpu_count = num_cpus::get();
 
     for _ in 0..cpu_count {
         let task_rx = task_rx.clone();
         let pair_tx = pair_tx.clone();
+        let ignored = ignored.clone();
         thread::spawn(move || {
             while let Ok(task) = task_rx.recv() {
                 let (_, image1) = task.index1;
                 let (_, image2) = task.index2;
                 let result = cosine_similarity(&image1.signature, &image2.signature);
 
+                let kind = match (image1.pixel_hash, image2.pixel_hash) {
+                    (Some(a), Some(b)) if a == b => Match::Pixel,
+                    _ => Match::Perceptual,
+                };
                 let pairing = Pairing {
                     index1: task.index1,
                     index2: task.index2,
                     score: result,
+                    kind,
                 };
 
-                if pairing.score > threshold {
+                // Checked last, since it hashes both files the first time.
+                if pairing.score > threshold && !ignored.contains(&image1.path, &image2.path) {
                     pair_tx
                         .send(pairing)
                         .expect("Unable to send pairing over channel");
@@ -389,11 +489,7 @@ fn spawn_cosine_threads(threshold: f64, task_rx: Receiver<CompareTask>, pair_tx:
 }
 
 fn main_signatures(cli: Cli) {
-    let db_path = cli
-        .database_file
-        .as_ref()
-        .map(|path| PathBuf::from(path))
-        .or_else(|| platform_dirs::AppDirs::new(Some("simagef"), false).map(|v| v.cache_dir));
+    let db_path = database::database_path(&cli);
 
     if cli.print_database_location {
         println!(
@@ -406,14 +502,17 @@ fn main_signatures(cli: Cli) {
         exit(0);
     }
 
+    let mut ignored = IgnoredPairs::default();
     let db_path = if !cli.no_database {
         let db_path = db_path.expect("Unable to figure out database path");
-        let conn = Connection::open(&db_path).expect("Unable to open SQLite database");
-        database::init(&conn).expect("Unable to initialize database");
+        let conn = database::open(&db_path);
+        ignored = IgnoredPairs::load(&conn).expect("Unable to load ignored pairs");
         Some(db_path)
     } else {
         None
     };
+    let review_db_path = db_path.clone();
+    let db_path_for_index = db_path.clone();
 
     let (insert_tx, insert_rx) = crossbeam::channel::bounded(2048);
 
@@ -432,44 +531,78 @@ fn main_signatures(cli: Cli) {
 
     let (filename_tx, filename_rx) = crossbeam::channel::bounded(FILENAME_CHANNEL_BOUND);
 
-    let mut dash_mode = false;
+    let files = input_files(&cli);
+    let cross_set = !cli.reference.is_empty();
 
-    if cli.files.is_empty() {
+    if files.is_empty() {
         eprintln!("No files provided");
         exit(1);
     }
 
-    let (calc_total_tx, calc_total_rx) = crossbeam::channel::bounded(2);
+    let (calc_total_tx, calc_total_rx) = crossbeam::channel::bounded(CHANNEL_BOUND);
     let (calc_count_tx, calc_count_rx) = crossbeam::channel::bounded(CHANNEL_BOUND);
 
     if !cli.pairs {
         thread::spawn(move || {
             progress_bar_loop(calc_total_rx, calc_count_rx);
         });
+    } else {
+        drop(calc_total_rx);
+        drop(calc_count_rx);
     }
 
-    let mut total_filenames = 0;
-
-    for arg_filename in &cli.files {
-        if arg_filename == "-" {
-            dash_mode = true;
-        } else {
-            filename_tx
-                .send(arg_filename.clone())
-                .expect("Unable to send filename to channel");
-            total_filenames += 1;
-        }
-    }
-
-    calc_total_tx.try_send(total_filenames).ok();
+    let filter = InputFilter::from_cli(&cli).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
 
-    read_filenames(filename_tx, dash_mode, calc_total_tx);
+    let sniff_stats = Arc::new(SniffStats::default());
+    let sniff_stats_clone = sniff_stats.clone();
+    let options = FetchOptions::from_cli(&cli);
+
+    // Exact copies are looked for first, except between a query and a
+    // reference set, where every image has to be compared on its own.
+    let exact_pass = if cross_set {
+        read_inputs(
+            files,
+            InputOptions::from_cli(&cli),
+            filter,
+            filename_tx,
+            calc_total_tx,
+        );
+        None
+    } else {
+        let (input_tx, input_rx) = crossbeam::channel::bounded(FILENAME_CHANNEL_BOUND);
+        read_inputs(
+            files,
+            InputOptions::from_cli(&cli),
+            filter,
+            input_tx,
+            calc_total_tx,
+        );
+        Some(exact::spawn_exact_pass(
+            input_rx,
+            filename_tx,
+            calc_count_tx.clone(),
+            db_path.clone(),
+            sniff_stats.clone(),
+            cli.exact,
+        ))
+    };
 
     let (img_tx, img_rx) =
         crossbeam::channel::bounded::<&'static SignatureToCompare>(CHANNEL_BOUND);
 
     thread::spawn(move || {
-        spawn_signature_threads(filename_rx, img_tx, calc_count_tx, db_path, insert_tx);
+        spawn_signature_threads(
+            filename_rx,
+            img_tx,
+            calc_count_tx,
+            db_path,
+            insert_tx,
+            sniff_stats_clone,
+            options,
+        );
     });
 
     // Image task channel
@@ -478,38 +611,64 @@ fn main_signatures(cli: Cli) {
     // Image list return channel
     let (ret_tx, ret_rx) = crossbeam::channel::bounded(1);
 
+    let index_db_path = db_path_for_index.clone();
     thread::spawn(move || {
-        use lsh_rs2::prelude::*;
-
-        let bucket_width = get_bucket_width(threshold_u8);
-        // println!("Bucket width is {}", bucket_width);
-        let n_projections = 5;
-        let n_hash_tables = 20;
-        let dim = 625;
-        let mut lsh = LshMem::<_, f32>::new(n_projections, n_hash_tables, dim)
-            .seed(4001)
-            .only_index()
-            .l2(bucket_width)
-            .expect("Unable to set up LSH");
+        // Signatures that are already in the database are found through the
+        // index stored along with them, so only new and changed signatures
+        // are hashed into an index of this run's own.
+        let stored = index_db_path.and_then(|db_path| {
+            let conn = Connection::open(db_path).ok()?;
+            let mut index = PersistentIndex::open(&conn, threshold_u8).ok()?;
+            index.sync(&conn).ok()?;
+            Some(index)
+        });
+        let mut lsh = new_index(threshold_u8);
+        // The image behind each id in `lsh`.
+        let mut lsh_images: Vec<usize> = Vec::new();
+        // The images whose signatures are in the stored index, by path.
+        let mut stored_images: HashMap<&Path, Vec<usize>> = HashMap::new();
 
         let mut images: Vec<(usize, &'static SignatureToCompare)> = Vec::new();
 
         while let Ok(image) = img_rx.recv() {
-            let signature: Vec<_> = image.signature.iter().map(|v| *v as f32).collect();
-            let results = lsh
+            let index1 = images.len();
+            let signature = to_vector(&image.signature);
+            let mut results: Vec<usize> = lsh
                 .query_bucket_ids(&signature)
-                .expect("Unable to query bucket");
-            let index1: usize = lsh
-                .store_vec(&signature)
-                .expect("Unable to store signature")
-                .try_into()
-                .unwrap();
+                .expect("Unable to query bucket")
+                .into_iter()
+                .map(|id| lsh_images[id as usize])
+                .collect();
+            if let Some(stored) = &stored {
+                let candidates = stored
+                    .candidates(&image.signature)
+                    .expect("Unable to query bucket");
+                for path in candidates {
+                    results.extend(stored_images.get(path).into_iter().flatten());
+                }
+            }
+
+            let in_stored = stored
+                .as_ref()
+                .is_some_and(|stored| stored.contains(&image.canonical));
+            if image.unchanged && in_stored {
+                stored_images
+                    .entry(&image.canonical)
+                    .or_default()
+                    .push(index1);
+            } else {
+                lsh.store_vec(&signature)
+                    .expect("Unable to store signature");
+                lsh_images.push(index1);
+            }
 
             let ipair = (index1, image);
             images.push(ipair);
 
             for index2 in results {
-                let index2: usize = index2.try_into().expect("Unable to convert u32 to usize");
+                if !image.compares_with(images[index2].1) {
+                    continue;
+                }
                 task_tx
                     .send(CompareTask {
                         index1: ipair,
@@ -519,49 +678,129 @@ fn main_signatures(cli: Cli) {
             }
         }
 
-        ret_tx.send(images).expect("Unable to send image list back");
+        ret_tx
+            .send((images, stored))
+            .expect("Unable to send image list back");
     });
 
     // Image pairing channel
     let (pair_tx, pair_rx) = crossbeam::channel::bounded(CHANNEL_BOUND);
 
-    spawn_cosine_threads(threshold, task_rx, pair_tx);
+    spawn_cosine_threads(threshold, task_rx, pair_tx, Arc::new(ignored));
 
     let mut pairings = Vec::new();
 
     let executable = get_executable(&cli);
+    let mut printer = Printer::new(cli.format);
 
     while let Ok(pair) = pair_rx.recv() {
+        let pair = pair.query_first();
         // If we use pairs, we execute for each pair right away.
         if cli.pairs {
             let (_, image1) = pair.index1;
             let (_, image2) = pair.index2;
-            let filename1 = &image1.path;
-            let filename2 = &image2.path;
-            print_fmt(&vec![filename1, filename2], cli.format);
-            #[cfg(not(feature = "no-exec"))]
-            if let Some((program, args)) = &executable {
-                Command::new(program)
-                    .args(args)
-                    .arg(filename1)
-                    .arg(filename2)
-                    .output()
-                    .expect("Unable to run executable provided");
-            }
+            print_pair_and_exec(
+                &image1.path,
+                &image2.path,
+                pair.score,
+                pair.kind,
+                &executable,
+                &mut printer,
+            );
         }
         pairings.push(pair);
     }
 
+    let exact = exact_pass
+        .map(|thread| thread.join().expect("Exact duplicate search error"))
+        .unwrap_or_default();
+    if cli.pairs {
+        for set in &exact {
+            for copy in &set[1..] {
+                print_pair_and_exec(&set[0], copy, 1.0, Match::Exact, &executable, &mut printer);
+            }
+        }
+    }
+
     if let Some(thread) = insertion_thread {
         thread.join().expect("Database insertion thread error");
     }
 
-    let images = ret_rx.recv().unwrap();
+    let (images, stored_index) = ret_rx.recv().unwrap();
+    // Signatures stored during the run are added to the stored index, so the
+    // next run finds them there.
+    if let (Some(mut index), Some(db_path)) = (stored_index, &db_path_for_index) {
+        let result = Connection::open(db_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut conn| {
+                index.sync(&conn)?;
+                if index.is_changed() {
+                    index.save(&mut conn)?;
+                }
+                Ok(())
+            });
+        if let Err(e) = result {
+            eprintln!("Unable to store index: {}", e);
+        }
+    }
 
-    let image_map: Vec<String> = images.iter().map(|(_, s)| s.path.clone()).collect();
+    let image_map: Vec<PathBuf> = images.iter().map(|(_, s)| s.path.clone()).collect();
 
-    if !cli.pairs {
-        make_groups_and_exec(&image_map, pairings, &executable, cli.format);
+    if !cli.pairs || cli.report.is_some() || cli.contact_sheet.is_some() {
+        let pairs: Vec<IndexPair> = pairings
+            .iter()
+            .map(|pair| (pair.index1.0, pair.index2.0, pair.score, pair.kind))
+            .collect();
+        let query_groups = cross_set.then(|| make_query_groups(pairings.iter().copied()));
+        let groups = arrange_groups(&image_map, &pairs, query_groups, cli.cluster, threshold);
+        let mut groups = exact::merge_exact(
+            groups,
+            &exact,
+            cross_set || cli.cluster == cli::Cluster::Star,
+        );
+        keep::choose_keepers(&mut groups, &cli.keep);
+        if let Some(report) = &cli.report {
+            if let Err(e) = report::write_report(report, &groups) {
+                eprintln!("{}: {}", report.display(), e);
+            }
+        }
+        if let Some(dir) = &cli.contact_sheet {
+            if let Err(e) = contact_sheet::write_contact_sheets(dir, &groups) {
+                eprintln!("{}: {}", dir.display(), e);
+            }
+        }
+        match (&cli.command, &cli.emit_script) {
+            // The review applies its own actions and prints nothing.
+            (Some(cli::Command::Review(args)), _) => {
+                review::main_review(&groups, args, review_db_path.as_deref());
+                return;
+            }
+            (_, Some(kind)) => script::print_script(&groups, kind),
+            _ if !cli.pairs => {
+                print_groups_and_exec(&groups, &executable, &mut printer);
+                // Query groups already hold every pair.
+                if !cross_set {
+                    printer
+                        .pairs_between_groups(&pairs_between_groups(&image_map, &pairs, &groups));
+                }
+            }
+            _ => (),
+        }
+        printer.finish();
+        if let Some(action) = &cli.action {
+            action::run_action(
+                &groups,
+                action,
+                cli.apply && !cli.dry_run,
+                cli.journal.as_deref(),
+            );
+        }
+    } else {
+        printer.finish();
+    }
+
+    if let Some(summary) = sniff_stats.summary() {
+        eprintln!("{}", summary);
     }
 }
 
@@ -577,7 +816,7 @@ fn main_pixel(_cli: Cli) {
 }
 
 fn main() {
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
 
     if cli.database_file.is_some() && cli.no_database {
         eprintln!("Database file specified, but database is disabled.");
@@ -595,9 +834,111 @@ fn main() {
         exit(1);
     }
 
+    if let Some(cli::Command::Undo(args)) = &cli.command {
+        action::main_undo(&args.journal);
+        return;
+    }
+
+    if let Some(cli::Command::Review(args)) = &cli.command {
+        if cli.pairs {
+            eprintln!("Review goes through groups, so it can't be used with --pairs.");
+            exit(1);
+        }
+        // Review searches the same way as running without a subcommand.
+        cli.files = args.files.clone();
+    } else if let Some(command) = &cli.command {
+        if cli.no_database || cli.pixels {
+            eprintln!("Subcommands need the database and can't be used in pixel mode.");
+            exit(1);
+        }
+        match command {
+            cli::Command::Query(args) => query::main_query(&cli, args),
+            cli::Command::Watch(args) => watch::main_watch(&cli, args),
+            cli::Command::Ignore(args) => ignored::main_ignore(&cli, args),
+            cli::Command::Db(args) => maintenance::main_db(&cli, args),
+            cli::Command::Review(_) | cli::Command::Undo(_) => unreachable!(),
+        }
+        return;
+    }
+
+    if !cli.reference.is_empty() && cli.cluster != cli::Cluster::Components {
+        eprintln!(
+            "--cluster doesn't apply to --reference, which groups matches around each query."
+        );
+        exit(1);
+    }
+
+    if !cli.keep.is_empty() && cli.pairs && cli.report.is_none() && cli.contact_sheet.is_none() {
+        eprintln!("--keep picks an image from each group, but --pairs doesn't print groups.");
+        exit(1);
+    }
+
+    if cli.pixels && !cli.reference.is_empty() {
+        eprintln!("Reference and query sets cannot be used in pixel mode.");
+        exit(1);
+    }
+
     if cli.pixels {
         main_pixel(cli);
     } else {
         main_signatures(cli);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn image(path: &str, set: InputSet) -> &'static SignatureToCompare {
+        Box::leak(Box::new(SignatureToCompare {
+            path: PathBuf::from(path),
+            canonical: PathBuf::from(path.trim_start_matches("./")),
+            set,
+            signature: Vec::new(),
+            pixel_hash: None,
+            unchanged: false,
+        }))
+    }
+
+    fn pairing(
+        a: (usize, &'static SignatureToCompare),
+        b: (usize, &'static SignatureToCompare),
+        score: f64,
+    ) -> Pairing {
+        Pairing {
+            index1: a,
+            index2: b,
+            score,
+            kind: Match::Perceptual,
+        }
+    }
+
+    #[test]
+    fn test_compares_with() {
+        let query = image("./a.png", InputSet::Query);
+        let same = image("a.png", InputSet::Reference);
+        let other = image("b.png", InputSet::Reference);
+        assert!(query.compares_with(other));
+        assert!(!query.compares_with(same));
+        assert!(!other.compares_with(same));
+        assert!(image("c.png", InputSet::All).compares_with(image("d.png", InputSet::All)));
+    }
+
+    #[test]
+    fn test_make_query_groups() {
+        let query = (0, image("./q.png", InputSet::Query));
+        let itself = (1, image("q.png", InputSet::Reference));
+        let close = (2, image("close.png", InputSet::Reference));
+        let closer = (3, image("closer.png", InputSet::Reference));
+        let other_query = (4, image("r.png", InputSet::Query));
+
+        let groups = make_query_groups([
+            pairing(close, query, 0.91),
+            pairing(query, itself, 1.0),
+            pairing(query, closer, 0.97),
+            pairing(other_query, close, 0.95),
+        ]);
+        assert_eq!(groups, vec![vec![0, 3, 2], vec![4, 2]]);
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     collections::HashMap,
-    io::BufRead,
+    path::{Path, PathBuf},
     process::{exit, Command},
     sync::{mpsc::channel, Arc, RwLock},
     thread,
@@ -11,42 +11,53 @@ use image::Rgb;
 use image_compare::BlendInput;
 
 use crate::{
-    cli::Cli,
-    formatting::print_fmt,
+    action::run_action,
+    cli::{self, Cli},
+    contact_sheet::write_contact_sheets,
+    filter::InputFilter,
+    formatting::{Match, Printer},
+    input::{input_files, read_inputs, InputFile, InputOptions},
+    keep::choose_keepers,
     open_image::{open_image, resize_as_needed, IBoft, SingleImage},
-    shared::{get_executable, make_groups_and_exec, CompareTask, Pairing},
+    report::write_report,
+    review::main_review,
+    script::print_script,
+    shared::{
+        arrange_groups, get_executable, pairs_between_groups, print_groups_and_exec, CompareTask,
+        IndexPair, Pairing,
+    },
 };
 struct ImageToCompare {
-    path: String,
+    path: PathBuf,
     image: IBoft,
 }
 
 impl SingleImage<IBoft> for ImageToCompare {
-    fn path(&self) -> &str {
+    fn path(&self) -> &Path {
         &self.path
     }
 }
 
 fn image_maker_loop(
-    filename_rx: Receiver<String>,
+    filename_rx: Receiver<InputFile>,
     tx: std::sync::mpsc::Sender<ImageToCompare>,
     width: u32,
     height: u32,
 ) {
     loop {
         match filename_rx.recv() {
-            Ok(filename) => {
+            Ok(InputFile { path: filename, .. }) => {
                 let image = open_image(&filename);
                 match image {
                     Ok(image) => {
                         let image = resize_as_needed(image, width, height);
                         tx.send(ImageToCompare {
-                            path: filename.to_string(),
+                            path: filename,
                             image,
                         })
                         .expect("Unable to send image to channel");
                     }
-                    Err(e) => eprintln!("Unable to open image {}: {}", filename, e),
+                    Err(e) => eprintln!("Unable to open image {}: {}", filename.display(), e),
                 }
             }
             Err(_) => {
@@ -60,7 +71,7 @@ fn image_maker_loop(
 
 struct ImageBundle {
     image_map: Vec<ImageToCompare>,
-    name_map: HashMap<String, usize>,
+    name_map: HashMap<PathBuf, usize>,
 }
 
 impl ImageBundle {
@@ -82,49 +93,28 @@ pub fn main_images(cli: Cli) {
 
     let (filename_tx, filename_rx) = crossbeam::channel::unbounded();
 
-    let mut dash_mode = false;
+    let files = input_files(&cli);
 
-    if cli.files.is_empty() {
+    if files.is_empty() {
         eprintln!("No files provided");
         exit(1);
     }
 
-    for arg_filename in &cli.files {
-        if arg_filename == "-" {
-            dash_mode = true;
-        } else {
-            filename_tx
-                .send(arg_filename.clone())
-                .expect("Unable to send filename to channel");
-        }
-    }
+    // There is no progress bar in pixel mode, so the totals go nowhere.
+    let (calc_total_tx, _) = crossbeam::channel::bounded(1);
 
-    if dash_mode {
-        thread::spawn(move || {
-            let mut stdin_lock = std::io::stdin().lock();
-            let mut buf = String::new();
-            loop {
-                match stdin_lock.read_line(&mut buf) {
-                    Ok(len) => {
-                        if len == 0 {
-                            return;
-                        }
-                        let slice = buf[0..(len - 1)].to_owned();
-                        filename_tx
-                            .send(slice)
-                            .expect("Unable to send filename to channel");
-                    }
-                    Err(err) => {
-                        eprintln!("{}", err);
-                        return;
-                    }
-                }
-                buf.clear();
-            }
-        });
-    } else {
-        drop(filename_tx);
-    }
+    let filter = InputFilter::from_cli(&cli).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+
+    read_inputs(
+        files,
+        InputOptions::from_cli(&cli),
+        filter,
+        filename_tx,
+        calc_total_tx,
+    );
 
     // let image_map: HashMap<String, ImageToCompare> = HashMap::new();
     // let image_map = Arc::new(RwLock::new(image_map));
@@ -157,7 +147,7 @@ pub fn main_images(cli: Cli) {
                     match bundle_arc.write() {
                         Ok(mut bundle) => {
                             if bundle.name_map.contains_key(image.path()) {
-                                eprintln!("Image already in list: {}", image.path());
+                                eprintln!("Image already in list: {}", image.path().display());
                                 continue;
                             }
 
@@ -242,6 +232,7 @@ pub fn main_images(cli: Cli) {
     let mut pairings = Vec::new();
 
     let executable = get_executable(&cli);
+    let mut printer = Printer::new(cli.format);
 
     // If we use pairs, we execute for each pair right away.
     while let Ok(pair) = pair_rx.recv() {
@@ -251,7 +242,7 @@ pub fn main_images(cli: Cli) {
                 .expect("Unable to read image bundle for pairs");
             let filename1 = bundle.image_map[pair.index1].path.clone();
             let filename2 = bundle.image_map[pair.index2].path.clone();
-            print_fmt(&vec![&filename1, &filename2], cli.format);
+            printer.pair(&filename1, &filename2, pair.score, Match::Perceptual);
             #[cfg(not(feature = "no-exec"))]
             if let Some((program, args)) = &executable {
                 Command::new(program)
@@ -283,9 +274,48 @@ pub fn main_images(cli: Cli) {
         .read()
         .expect("Unable to read image bundle after all threads have completed.");
 
-    let name_map: Vec<String> = bundle.image_map.iter().map(|s| s.path.clone()).collect();
-
-    if !cli.pairs {
-        make_groups_and_exec(&name_map, pairings, &executable);
+    let name_map: Vec<PathBuf> = bundle.image_map.iter().map(|s| s.path.clone()).collect();
+
+    if !cli.pairs || cli.report.is_some() || cli.contact_sheet.is_some() {
+        let pairs: Vec<IndexPair> = pairings
+            .iter()
+            .map(|pair| (pair.index1, pair.index2, pair.score, Match::Perceptual))
+            .collect();
+        let mut groups = arrange_groups(&name_map, &pairs, None, cli.cluster, threshold);
+        choose_keepers(&mut groups, &cli.keep);
+        if let Some(report) = &cli.report {
+            if let Err(e) = write_report(report, &groups) {
+                eprintln!("{}: {}", report.display(), e);
+            }
+        }
+        if let Some(dir) = &cli.contact_sheet {
+            if let Err(e) = write_contact_sheets(dir, &groups) {
+                eprintln!("{}: {}", dir.display(), e);
+            }
+        }
+        match (&cli.command, &cli.emit_script) {
+            // The review applies its own actions and prints nothing.
+            (Some(cli::Command::Review(args)), _) => {
+                main_review(&groups, args, None);
+                return;
+            }
+            (_, Some(kind)) => print_script(&groups, kind),
+            _ if !cli.pairs => {
+                print_groups_and_exec(&groups, &executable, &mut printer);
+                printer.pairs_between_groups(&pairs_between_groups(&name_map, &pairs, &groups));
+            }
+            _ => (),
+        }
+        printer.finish();
+        if let Some(action) = &cli.action {
+            run_action(
+                &groups,
+                action,
+                cli.apply && !cli.dry_run,
+                cli.journal.as_deref(),
+            );
+        }
+    } else {
+        printer.finish();
     }
 }
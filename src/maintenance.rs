@@ -0,0 +1,192 @@
+use std::{collections::BTreeMap, path::Path, process::exit};
+
+use rusqlite::Connection;
+
+use crate::{
+    cli::{Cli, DbArgs, DbCommand, PruneArgs},
+    database::{self, Entry, SIGNATURE_LEN},
+    formatting::format_time,
+    report::format_size,
+};
+
+fn file_size(db_path: &Path) -> u64 {
+    std::fs::metadata(db_path)
+        .map(|stat| stat.len())
+        .unwrap_or(0)
+}
+
+/// How many entries each directory holds directly, by directory.
+fn count_by_dir(entries: &[Entry]) -> BTreeMap<&Path, usize> {
+    let mut counts = BTreeMap::new();
+    for entry in entries {
+        let dir = entry.path.parent().unwrap_or(&entry.path);
+        *counts.entry(dir).or_default() += 1;
+    }
+    counts
+}
+
+fn stats(conn: &Connection, db_path: &Path) -> anyhow::Result<()> {
+    let entries = database::fetch_entries(conn)?;
+    println!("Database: {}", db_path.display());
+    println!("Size: {}", format_size(file_size(db_path)));
+    println!("Signatures: {}", entries.len());
+    let algorithm = database::signature_algorithm();
+    let outdated = entries
+        .iter()
+        .filter(|entry| entry.algorithm.as_ref() != Some(&algorithm))
+        .count();
+    if outdated > 0 {
+        println!("From another algorithm: {}", outdated);
+    }
+    // By the modification time of the file, since when a signature was
+    // stored isn't recorded.
+    if let Some(oldest) = entries.iter().min_by_key(|entry| entry.modified) {
+        println!(
+            "Oldest file: {} UTC {}",
+            format_time(oldest.modified),
+            oldest.path.display()
+        );
+    }
+
+    let counts = count_by_dir(&entries);
+    if !counts.is_empty() {
+        println!();
+    }
+    for (dir, count) in counts {
+        println!("{:>8} {}", count, dir.display());
+    }
+    Ok(())
+}
+
+/// The rows to remove: everything under `prefix` if there is one, otherwise
+/// the files that are gone. Files that can't be checked are kept.
+fn prune_ids(entries: &[Entry], prefix: Option<&Path>) -> Vec<i64> {
+    entries
+        .iter()
+        .filter(|entry| match prefix {
+            Some(prefix) => entry.path.starts_with(prefix),
+            None => matches!(entry.path.try_exists(), Ok(false)),
+        })
+        .map(|entry| entry.id)
+        .collect()
+}
+
+fn prune(conn: &mut Connection, args: &PruneArgs) -> anyhow::Result<()> {
+    // The directory may be gone already, in which case it can't be
+    // canonicalized.
+    let prefix = match &args.prefix {
+        Some(prefix) => {
+            Some(std::fs::canonicalize(prefix).or_else(|_| std::path::absolute(prefix))?)
+        }
+        None => None,
+    };
+    let entries = database::fetch_entries(conn)?;
+    let ids = prune_ids(&entries, prefix.as_deref());
+    database::delete_entries(conn, &ids)?;
+    println!("Removed {} of {} signatures", ids.len(), entries.len());
+    Ok(())
+}
+
+fn vacuum(conn: &Connection, db_path: &Path) -> anyhow::Result<()> {
+    let before = file_size(db_path);
+    database::vacuum(conn)?;
+    println!(
+        "{} -> {}",
+        format_size(before),
+        format_size(file_size(db_path))
+    );
+    Ok(())
+}
+
+/// Prints each problem found. Returns whether there were any.
+fn check(conn: &Connection) -> anyhow::Result<bool> {
+    let problems = database::integrity_check(conn)?;
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    let algorithm = database::signature_algorithm();
+    let mut wrong_length = 0;
+    for entry in database::fetch_entries(conn)? {
+        if entry.algorithm.as_ref() != Some(&algorithm)
+            || entry.signature_len == Some(SIGNATURE_LEN)
+        {
+            continue;
+        }
+        wrong_length += 1;
+        match entry.signature_len {
+            Some(len) => println!(
+                "{}: signature is {} bytes instead of {}",
+                entry.path.display(),
+                len,
+                SIGNATURE_LEN
+            ),
+            None => println!("{}: no signature", entry.path.display()),
+        }
+    }
+    Ok(!problems.is_empty() || wrong_length > 0)
+}
+
+/// Runs one of the `db` subcommands. The database has to exist already, so a
+/// mistyped `--database-file` isn't created just to report that it's empty.
+pub fn main_db(cli: &Cli, args: &DbArgs) {
+    let db_path = database::database_path(cli).expect("Unable to figure out database path");
+    if !db_path.exists() {
+        eprintln!("{}: No such database", db_path.display());
+        exit(1);
+    }
+    let mut conn = database::open(&db_path);
+
+    let result = match &args.command {
+        DbCommand::Stats => stats(&conn, &db_path),
+        DbCommand::Prune(args) => prune(&mut conn, args),
+        DbCommand::Vacuum => vacuum(&conn, &db_path),
+        DbCommand::Check => match check(&conn) {
+            Ok(true) => exit(1),
+            Ok(false) => {
+                println!("ok");
+                Ok(())
+            }
+            Err(e) => Err(e),
+        },
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    fn entry(id: i64, path: &str) -> Entry {
+        Entry {
+            id,
+            path: PathBuf::from(path),
+            modified: 0,
+            signature_len: Some(SIGNATURE_LEN),
+            algorithm: Some(database::signature_algorithm()),
+        }
+    }
+
+    #[test]
+    fn test_prune_ids() {
+        let entries = vec![
+            entry(1, "/photos/a.jpg"),
+            entry(2, "/photos-old/b.jpg"),
+            entry(3, "/photos/2020/c.jpg"),
+        ];
+        assert_eq!(prune_ids(&entries, Some(Path::new("/photos"))), vec![1, 3]);
+        assert_eq!(
+            count_by_dir(&entries).into_iter().collect::<Vec<_>>(),
+            vec![
+                (Path::new("/photos"), 1),
+                (Path::new("/photos/2020"), 1),
+                (Path::new("/photos-old"), 1),
+            ]
+        );
+    }
+}
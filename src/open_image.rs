@@ -1,21 +1,60 @@
-use std::path::PathBuf;
+use std::{
+    fs::File,
+    io::{BufReader, Cursor},
+    path::Path,
+};
 
-use image::{ImageBuffer, Rgba, ImageResult};
+use image::{DynamicImage, ImageBuffer, ImageFormat, ImageReader, ImageResult, Rgba};
+
+use crate::shared::Hash;
 
 /** Image buffer of type. */
 pub type IBoft = ImageBuffer<Rgba<u8>, Vec<u8>>;
 
 #[cfg(feature = "pixel")]
 pub trait SingleImage<T> {
-    fn path(&self) -> &str;
+    fn path(&self) -> &Path;
 }
 
-pub fn open_image(filename: &str) -> ImageResult<IBoft> {
+pub fn open_image(filename: &Path) -> ImageResult<IBoft> {
     Ok(image::open(filename)?.into_rgba8())
 }
 
-pub fn open_image_path(filename: &PathBuf) -> ImageResult<IBoft> {
-    Ok(image::open(filename)?.into_rgba8())
+/** Decodes an image whose format is already known, e.g. from sniffing, in
+its own color type. */
+pub fn decode_image_path(filename: &Path, format: ImageFormat) -> ImageResult<DynamicImage> {
+    ImageReader::with_format(BufReader::new(File::open(filename)?), format).decode()
+}
+
+/** Decodes an image of a known format that has already been read into memory,
+in its own color type. */
+pub fn decode_image_bytes(bytes: &[u8], format: ImageFormat) -> ImageResult<DynamicImage> {
+    ImageReader::with_format(Cursor::new(bytes), format).decode()
+}
+
+/** Decodes an image whose format is already known, e.g. from sniffing. */
+pub fn open_image_path(filename: &Path, format: ImageFormat) -> ImageResult<IBoft> {
+    Ok(decode_image_path(filename, format)?.into_rgba8())
+}
+
+/** Hashes the color type, dimensions and pixels of a decoded image, which stay
+the same when an image is saved again without changing how it looks. The image
+is hashed before any conversion, so that an image with 16 bits per channel
+doesn't share its hash with the 8-bit version it would be converted to. */
+pub fn pixel_hash(image: &DynamicImage) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(format!("{:?}", image.color()).as_bytes());
+    hasher.update(&image.width().to_le_bytes());
+    hasher.update(&image.height().to_le_bytes());
+    hasher.update(image.as_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+/** Reads the width and height of an image from its header, without decoding it. */
+pub fn image_dimensions(filename: &Path) -> ImageResult<(u32, u32)> {
+    ImageReader::open(filename)?
+        .with_guessed_format()?
+        .into_dimensions()
 }
 
 #[cfg(feature = "pixel")]
@@ -27,3 +66,60 @@ pub fn resize_as_needed(image: IBoft, target_width: u32, target_height: u32) ->
         image::imageops::FilterType::Nearest,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Encodes an image and decodes it again.
+    fn round_trip(image: &DynamicImage, format: ImageFormat) -> DynamicImage {
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        decode_image_bytes(&bytes, format).unwrap()
+    }
+
+    #[test]
+    fn test_pixel_hash() {
+        let image = DynamicImage::ImageRgba8(IBoft::from_fn(3, 2, |x, y| {
+            Rgba([x as u8 * 80, y as u8 * 120, 40, 255])
+        }));
+        let png = round_trip(&image, ImageFormat::Png);
+        let tiff = round_trip(&image, ImageFormat::Tiff);
+        assert_eq!(pixel_hash(&png), pixel_hash(&tiff));
+        assert_eq!(pixel_hash(&png), pixel_hash(&image));
+
+        // The same pixels laid out differently aren't the same image.
+        let mut changed = image.to_rgba8();
+        changed.put_pixel(0, 0, Rgba([0, 0, 0, 255]));
+        let changed = DynamicImage::ImageRgba8(changed);
+        assert!(pixel_hash(&changed) != pixel_hash(&image));
+        let transposed = IBoft::from_raw(2, 3, image.as_bytes().to_vec()).unwrap();
+        let transposed = DynamicImage::ImageRgba8(transposed);
+        assert!(pixel_hash(&transposed) != pixel_hash(&image));
+    }
+
+    #[test]
+    fn test_pixel_hash_16_bit() {
+        // Both convert to the same 8-bit image, which differs in the low bits
+        // only.
+        let wide = |low: u16| {
+            DynamicImage::ImageRgba16(ImageBuffer::from_fn(2, 2, |x, _| {
+                Rgba([x as u16 * 0x4000 + low, 0x8000, 0x1200 + low, 0xffff])
+            }))
+        };
+        let (a, b) = (wide(0), wide(1));
+        assert_eq!(a.to_rgba8(), b.to_rgba8());
+        assert!(pixel_hash(&a) != pixel_hash(&b));
+        assert_eq!(
+            pixel_hash(&round_trip(&a, ImageFormat::Png)),
+            pixel_hash(&a)
+        );
+
+        // Nor is a 16-bit image the same as its 8-bit version.
+        let narrow = DynamicImage::ImageRgba8(a.to_rgba8());
+        assert!(pixel_hash(&narrow) != pixel_hash(&a));
+    }
+}
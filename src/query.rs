@@ -0,0 +1,92 @@
+use std::process::exit;
+
+use crate::{
+    cli::{Cli, QueryArgs},
+    database, fetch_signature,
+    formatting::{Match, Printer},
+    ignored::IgnoredPairs,
+    image_match_rs::cosine_similarity,
+    index::PersistentIndex,
+    sniff::SniffStats,
+    FetchOptions,
+};
+
+/// Looks up each image among the signatures already stored in the database and
+/// prints its best matches, one line per match with the score first. Query
+/// images that aren't cached yet are not added to the database, so checking
+/// an image doesn't make it part of the library. The index over the database
+/// is kept between runs, so only signatures added since are hashed.
+pub fn main_query(cli: &Cli, args: &QueryArgs) {
+    let db_path = database::database_path(cli).expect("Unable to figure out database path");
+    let mut conn = database::open(&db_path);
+
+    let prefix = args.prefix.as_ref().map(|prefix| {
+        std::fs::canonicalize(prefix).unwrap_or_else(|e| {
+            eprintln!("{}: {}", prefix.display(), e);
+            exit(1);
+        })
+    });
+
+    let threshold_u8 = cli.threshold.unwrap_or(90);
+    let threshold = f64::from(threshold_u8) * 0.01;
+
+    let mut index = PersistentIndex::open(&conn, threshold_u8).expect("Unable to load index");
+    let added = index.sync(&conn).expect("Unable to update index");
+    if added > 0 {
+        index.save(&mut conn).expect("Unable to store index");
+    }
+
+    let ignored = IgnoredPairs::load(&conn).expect("Unable to load ignored pairs");
+    let db_conn = Some(conn);
+    let conn = db_conn.as_ref().unwrap();
+    let sniff_stats = SniffStats::default();
+    let mut printer = Printer::new(cli.format).with_scores();
+
+    let options = FetchOptions::from_cli(cli);
+
+    for image in &args.images {
+        let signature = match fetch_signature(image, &db_conn, None, &sniff_stats, options) {
+            Ok(fetched) => fetched.signature,
+            Err(e) => {
+                eprintln!("{}: {}", image.display(), e);
+                continue;
+            }
+        };
+
+        let candidates = index
+            .candidates(&signature)
+            .expect("Unable to query bucket");
+        // The image itself is in the database if it was scanned before.
+        let canonical = std::fs::canonicalize(image).ok();
+
+        let mut matches: Vec<(database::Signature, f64)> = Vec::new();
+        for path in candidates {
+            if canonical.as_deref() == Some(path) {
+                continue;
+            }
+            if prefix
+                .as_ref()
+                .is_some_and(|prefix| !path.starts_with(prefix))
+            {
+                continue;
+            }
+            match database::fetch_stored(conn, path) {
+                Ok(Some(entry)) => {
+                    let score = cosine_similarity(&signature, &entry.signature);
+                    if score > threshold && !ignored.contains(image, path) {
+                        matches.push((entry, score));
+                    }
+                }
+                Ok(None) => (),
+                Err(e) => eprintln!("{}: {}", path.display(), e),
+            }
+        }
+        matches.sort_by(|a, b| b.1.total_cmp(&a.1));
+        matches.truncate(args.limit);
+
+        for (entry, score) in &matches {
+            printer.pair(image, &entry.path, *score, Match::Perceptual);
+        }
+    }
+    printer.finish();
+}
@@ -0,0 +1,212 @@
+use std::{collections::HashMap, fmt::Write, io::Cursor, path::Path, thread, time::UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageFormat};
+
+use crate::{
+    formatting::{escape_html, format_time, Group, Match},
+    open_image::{image_dimensions, open_image_path},
+    sniff::sniff_format,
+};
+
+/// The largest width or height of a thumbnail, in pixels.
+const THUMBNAIL_SIZE: u32 = 240;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; background: #f4f4f4; color: #222; }
+section { background: #fff; border-radius: 6px; padding: 1em; margin-bottom: 2em; }
+.images { display: flex; flex-wrap: wrap; gap: 1em; }
+figure { margin: 0; width: 240px; }
+figure .thumbnail { width: 240px; height: 240px; display: flex; align-items: center; justify-content: center; background: #ddd; }
+figure img { max-width: 240px; max-height: 240px; }
+figure.keeper .thumbnail { outline: 4px solid #2a7a3a; }
+.keep { display: inline-block; background: #2a7a3a; color: #fff; padding: 0 0.4em; border-radius: 3px; margin-bottom: 0.25em; }
+figcaption { font-size: 0.8em; margin-top: 0.5em; }
+.path { font-family: monospace; word-break: break-all; }
+table { border-collapse: collapse; margin-top: 1em; font-size: 0.8em; }
+td, th { border: 1px solid #ccc; padding: 0.25em 0.5em; text-align: left; }
+td.path { max-width: 40em; }
+";
+
+/// What the report shows about each image.
+struct ImageDetails {
+    dimensions: Option<(u32, u32)>,
+    size: Option<u64>,
+    modified: Option<u64>,
+    format: Option<ImageFormat>,
+    /// A JPEG thumbnail, base64 encoded.
+    thumbnail: Option<String>,
+}
+
+impl ImageDetails {
+    fn new(path: &Path) -> ImageDetails {
+        let stat = std::fs::metadata(path).ok();
+        let format = sniff_format(path).ok().flatten();
+        ImageDetails {
+            dimensions: image_dimensions(path).ok(),
+            size: stat.as_ref().map(|stat| stat.len()),
+            modified: stat
+                .and_then(|stat| stat.modified().ok())
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs()),
+            format,
+            thumbnail: format.and_then(|format| thumbnail(path, format).ok()),
+        }
+    }
+}
+
+fn thumbnail(path: &Path, format: ImageFormat) -> anyhow::Result<String> {
+    let image = DynamicImage::ImageRgba8(open_image_path(path, format)?);
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+    let mut jpeg = Cursor::new(Vec::new());
+    JpegEncoder::new_with_quality(&mut jpeg, 80).encode_image(&thumbnail)?;
+    Ok(STANDARD.encode(jpeg.into_inner()))
+}
+
+/// Decoding the images for their thumbnails is by far the slowest part, so
+/// it's spread over all cores.
+fn collect_details<'a>(groups: &[Group<'a>]) -> HashMap<&'a Path, ImageDetails> {
+    let mut paths: Vec<&Path> = groups
+        .iter()
+        .flat_map(|group| group.members.iter().copied())
+        .collect();
+    paths.sort();
+    paths.dedup();
+
+    let chunk_size = paths.len().div_ceil(num_cpus::get()).max(1);
+    thread::scope(|scope| {
+        let threads: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|path| (*path, ImageDetails::new(path)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .flat_map(|thread| thread.join().expect("Unable to read image details"))
+            .collect()
+    })
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn render(groups: &[Group], details: &HashMap<&Path, ImageDetails>) -> String {
+    let path_html = |path: &Path| escape_html(&path.to_string_lossy());
+    let mut html = String::new();
+
+    // Writing to a String can't fail.
+    write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Similar images</title>\n<style>{}</style>\n</head>\n<body>\n\
+         <h1>Similar images</h1>\n<p>{} groups, {} images</p>\n",
+        STYLE,
+        groups.len(),
+        details.len()
+    )
+    .unwrap();
+
+    for (i, group) in groups.iter().enumerate() {
+        write!(
+            html,
+            "<section>\n<h2>Group {}</h2>\n<div class=\"images\">\n",
+            i + 1
+        )
+        .unwrap();
+        for path in &group.members {
+            let image = &details[path];
+            if group.keeper == Some(*path) {
+                html.push_str("<figure class=\"keeper\">\n<div class=\"thumbnail\">");
+            } else {
+                html.push_str("<figure>\n<div class=\"thumbnail\">");
+            }
+            match &image.thumbnail {
+                Some(thumbnail) => write!(
+                    html,
+                    "<img src=\"data:image/jpeg;base64,{}\" alt=\"\">",
+                    thumbnail
+                )
+                .unwrap(),
+                None => html.push_str("No preview"),
+            }
+            html.push_str("</div>\n<figcaption>\n");
+            if group.keeper == Some(*path) {
+                html.push_str("<div class=\"keep\">Keep</div>\n");
+            }
+            writeln!(html, "<div class=\"path\">{}</div>", path_html(path)).unwrap();
+
+            let mut facts = Vec::new();
+            if let Some((width, height)) = image.dimensions {
+                facts.push(format!("{} &times; {}", width, height));
+            }
+            if let Some(size) = image.size {
+                facts.push(format_size(size));
+            }
+            if let Some(format) = image.format {
+                facts.push(format!("{:?}", format));
+            }
+            writeln!(html, "<div>{}</div>", facts.join(" &middot; ")).unwrap();
+            if let Some(modified) = image.modified {
+                writeln!(html, "<div>Modified {} UTC</div>", format_time(modified)).unwrap();
+            }
+            html.push_str("</figcaption>\n</figure>\n");
+        }
+        html.push_str("</div>\n<table>\n<tr><th>Image</th><th>Image</th><th>Score</th></tr>\n");
+        for (a, b, score, kind) in &group.pairs {
+            let score = match kind {
+                Match::Perceptual => format!("{:.4}", score),
+                kind => kind.to_string(),
+            };
+            writeln!(
+                html,
+                "<tr><td class=\"path\">{}</td><td class=\"path\">{}</td><td>{}</td></tr>",
+                path_html(a),
+                path_html(b),
+                score
+            )
+            .unwrap();
+        }
+        html.push_str("</table>\n</section>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Writes a self-contained HTML page showing every group, with thumbnails
+/// embedded in the page itself.
+pub fn write_report(path: &Path, groups: &[Group]) -> std::io::Result<()> {
+    let details = collect_details(groups);
+    std::fs::write(path, render(groups, &details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(2613244), "2.5 MB");
+    }
+}
@@ -0,0 +1,723 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    io::{self, Cursor, Write},
+    path::Path,
+    process::exit,
+    time::UNIX_EPOCH,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use crossterm::{
+    cursor::MoveTo,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    queue,
+};
+use image::{DynamicImage, ImageFormat, RgbImage};
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Clear, List, ListItem, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+
+use rusqlite::Connection;
+
+use crate::{
+    action::run_action,
+    cli::{Preview, ReviewArgs},
+    formatting::{format_time, Group, Match},
+    ignored,
+    open_image::{image_dimensions, open_image_path},
+    report::format_size,
+    sniff::sniff_format,
+};
+
+/// The cell size to assume when the terminal doesn't report its size in
+/// pixels.
+const CELL_SIZE: (u32, u32) = (8, 16);
+
+const HELP: &str =
+    "↑↓ image  ←→ group  k keep  d delete  o only keep this  u clear  s skip  x not duplicate  \
+     q finish  Esc quit";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mark {
+    Undecided,
+    Keep,
+    Delete,
+}
+
+/// The decisions made about a group, one mark per member.
+struct Decisions {
+    marks: Vec<Mark>,
+    not_duplicate: bool,
+}
+
+/// What is shown about each image.
+struct Details {
+    dimensions: Option<(u32, u32)>,
+    size: Option<u64>,
+    modified: Option<u64>,
+    format: Option<ImageFormat>,
+}
+
+impl Details {
+    fn new(path: &Path) -> Details {
+        let stat = std::fs::metadata(path).ok();
+        Details {
+            dimensions: image_dimensions(path).ok(),
+            size: stat.as_ref().map(|stat| stat.len()),
+            modified: stat
+                .and_then(|stat| stat.modified().ok())
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs()),
+            format: sniff_format(path).ok().flatten(),
+        }
+    }
+
+    fn summary(&self) -> String {
+        let mut facts = Vec::new();
+        if let Some((width, height)) = self.dimensions {
+            facts.push(format!("{} × {}", width, height));
+        }
+        if let Some(size) = self.size {
+            facts.push(format_size(size));
+        }
+        if let Some(format) = self.format {
+            facts.push(format!("{:?}", format));
+        }
+        if let Some(modified) = self.modified {
+            facts.push(format_time(modified));
+        }
+        facts.join(" · ")
+    }
+}
+
+enum Outcome {
+    Apply,
+    Quit,
+}
+
+struct Review<'g, 'a> {
+    groups: &'g [Group<'a>],
+    decisions: Vec<Decisions>,
+    group: usize,
+    list: ListState,
+    details: HashMap<&'a Path, Details>,
+    confirming: bool,
+    /// Where the preview goes, as of the last time the screen was drawn.
+    preview_area: Rect,
+}
+
+impl<'g, 'a> Review<'g, 'a> {
+    /// Images picked with `--keep` start out marked to be kept.
+    fn new(groups: &'g [Group<'a>]) -> Review<'g, 'a> {
+        let decisions = groups
+            .iter()
+            .map(|group| Decisions {
+                marks: group
+                    .members
+                    .iter()
+                    .map(|path| {
+                        if group.keeper == Some(*path) {
+                            Mark::Keep
+                        } else {
+                            Mark::Undecided
+                        }
+                    })
+                    .collect(),
+                not_duplicate: false,
+            })
+            .collect();
+        Review {
+            groups,
+            decisions,
+            group: 0,
+            list: ListState::default().with_selected(Some(0)),
+            details: HashMap::new(),
+            confirming: false,
+            preview_area: Rect::default(),
+        }
+    }
+
+    fn selected(&self) -> usize {
+        self.list.selected().unwrap_or(0)
+    }
+
+    fn go_to_group(&mut self, group: usize) {
+        self.group = group.min(self.groups.len() - 1);
+        self.list.select(Some(0));
+    }
+
+    /// The changes to make, as groups of an image to keep followed by the
+    /// images to delete, along with notes on groups that are left alone. An
+    /// image marked to be kept anywhere is never deleted.
+    fn changes(&self) -> (Vec<Group<'a>>, Vec<String>) {
+        let kept: HashSet<&Path> = self
+            .groups
+            .iter()
+            .zip(&self.decisions)
+            .flat_map(|(group, decisions)| {
+                group
+                    .members
+                    .iter()
+                    .zip(&decisions.marks)
+                    .filter(|(_, mark)| **mark == Mark::Keep)
+                    .map(|(path, _)| *path)
+            })
+            .collect();
+
+        let mut changes = Vec::new();
+        let mut notes = Vec::new();
+        for (i, (group, decisions)) in self.groups.iter().zip(&self.decisions).enumerate() {
+            if decisions.not_duplicate {
+                continue;
+            }
+            let marked = |wanted: Mark| {
+                group
+                    .members
+                    .iter()
+                    .zip(&decisions.marks)
+                    .filter(move |(_, mark)| **mark == wanted)
+                    .map(|(path, _)| *path)
+            };
+            let deletions: Vec<&Path> = marked(Mark::Delete)
+                .filter(|path| !kept.contains(path))
+                .collect();
+            if deletions.is_empty() {
+                continue;
+            }
+            let Some(keeper) = marked(Mark::Keep).next() else {
+                notes.push(format!(
+                    "Group {}: No image is marked to be kept, so none are deleted",
+                    i + 1
+                ));
+                continue;
+            };
+            changes.push(Group {
+                members: std::iter::once(keeper).chain(deletions).collect(),
+                pairs: Vec::new(),
+                keeper: Some(keeper),
+            });
+        }
+        (changes, notes)
+    }
+
+    fn handle(&mut self, key: KeyEvent) -> Option<Outcome> {
+        if self.confirming {
+            match key.code {
+                KeyCode::Char('y') => return Some(Outcome::Apply),
+                KeyCode::Char('n') | KeyCode::Esc => self.confirming = false,
+                _ => (),
+            }
+            return None;
+        }
+
+        let count = self.groups[self.group].members.len();
+        let selected = self.selected();
+        let decisions = &mut self.decisions[self.group];
+        match key.code {
+            KeyCode::Esc => return Some(Outcome::Quit),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Some(Outcome::Quit)
+            }
+            KeyCode::Char('q') => self.confirming = true,
+            KeyCode::Up => self.list.select(Some(selected.saturating_sub(1))),
+            KeyCode::Down => self.list.select(Some((selected + 1).min(count - 1))),
+            KeyCode::Left | KeyCode::PageUp => self.go_to_group(self.group.saturating_sub(1)),
+            KeyCode::Right | KeyCode::PageDown | KeyCode::Enter => self.go_to_group(self.group + 1),
+            KeyCode::Char('k') => decisions.marks[selected] = Mark::Keep,
+            KeyCode::Char('d') => decisions.marks[selected] = Mark::Delete,
+            KeyCode::Char('u') => decisions.marks[selected] = Mark::Undecided,
+            KeyCode::Char('o') => {
+                for (i, mark) in decisions.marks.iter_mut().enumerate() {
+                    *mark = if i == selected {
+                        Mark::Keep
+                    } else {
+                        Mark::Delete
+                    };
+                }
+            }
+            KeyCode::Char('s') => {
+                for mark in &mut decisions.marks {
+                    if *mark == Mark::Delete {
+                        *mark = Mark::Undecided;
+                    }
+                }
+                self.go_to_group(self.group + 1);
+            }
+            KeyCode::Char('x') => {
+                decisions.not_duplicate = !decisions.not_duplicate;
+                if decisions.not_duplicate {
+                    self.go_to_group(self.group + 1);
+                }
+            }
+            _ => (),
+        }
+        None
+    }
+
+    fn draw(&mut self, frame: &mut Frame, preview: Preview) {
+        let group = &self.groups[self.group];
+        for path in &group.members {
+            self.details
+                .entry(path)
+                .or_insert_with(|| Details::new(path));
+        }
+        let decisions = &self.decisions[self.group];
+
+        let pairs_height = (group.pairs.len() as u16 + 2).min(8);
+        let [header, body, pairs, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(6),
+            Constraint::Length(pairs_height),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [images, side] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(body);
+
+        let mut title = format!(
+            "Group {} of {} · {} images",
+            self.group + 1,
+            self.groups.len(),
+            group.members.len()
+        );
+        if decisions.not_duplicate {
+            title.push_str(" · not a duplicate");
+        }
+        frame.render_widget(
+            Line::from(title).style(Style::new().add_modifier(Modifier::BOLD)),
+            header,
+        );
+
+        let items: Vec<ListItem> = group
+            .members
+            .iter()
+            .zip(&decisions.marks)
+            .map(|(path, mark)| {
+                let (tag, color) = match mark {
+                    Mark::Undecided => ("[ ]", Color::Reset),
+                    Mark::Keep => ("[K]", Color::Green),
+                    Mark::Delete => ("[D]", Color::Red),
+                };
+                ListItem::new(vec![
+                    Line::from(vec![
+                        Span::styled(tag, Style::new().fg(color)),
+                        Span::raw(" "),
+                        Span::raw(path.to_string_lossy()),
+                    ]),
+                    Line::from(format!("    {}", self.details[path].summary()))
+                        .style(Style::new().add_modifier(Modifier::DIM)),
+                ])
+            })
+            .collect();
+        frame.render_stateful_widget(
+            List::new(items)
+                .block(Block::bordered().title("Images"))
+                .highlight_style(Style::new().add_modifier(Modifier::REVERSED)),
+            images,
+            &mut self.list,
+        );
+
+        let path = group.members[self.selected()];
+        if preview == Preview::None {
+            let best = group
+                .pairs
+                .iter()
+                .filter(|(a, b, _, _)| *a == path || *b == path)
+                .map(|(_, _, score, _)| *score)
+                .fold(None, |best: Option<f64>, score| {
+                    Some(best.map_or(score, |best| best.max(score)))
+                });
+            let mut lines = vec![Line::from(path.to_string_lossy()), Line::default()];
+            lines.push(Line::from(self.details[path].summary()));
+            if let Some(best) = best {
+                lines.push(Line::from(format!("Best score {:.4}", best)));
+            }
+            frame.render_widget(
+                Paragraph::new(lines)
+                    .wrap(Wrap { trim: false })
+                    .block(Block::bordered().title("Details")),
+                side,
+            );
+        } else {
+            let block = Block::bordered().title("Preview");
+            self.preview_area = block.inner(side);
+            frame.render_widget(block, side);
+        }
+
+        let name = |path: &Path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        let lines: Vec<Line> = group
+            .pairs
+            .iter()
+            .map(|(a, b, score, kind)| {
+                let score = match kind {
+                    Match::Perceptual => format!("{:.4}", score),
+                    kind => format!("{:<6}", kind.to_string()),
+                };
+                Line::from(format!("{}  {}  {}", score, name(a), name(b)))
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Pairs")),
+            pairs,
+        );
+
+        frame.render_widget(
+            Line::from(HELP).style(Style::new().add_modifier(Modifier::DIM)),
+            help,
+        );
+
+        if self.confirming {
+            let (changes, _) = self.changes();
+            let deletions: usize = changes.iter().map(|group| group.members.len() - 1).sum();
+            let not_duplicate = self
+                .decisions
+                .iter()
+                .filter(|decisions| decisions.not_duplicate)
+                .count();
+            let text = vec![
+                Line::from(format!("{} images marked for deletion.", deletions)),
+                Line::from(format!(
+                    "{} groups marked as not duplicates.",
+                    not_duplicate
+                )),
+                Line::default(),
+                Line::from("y apply these changes · n go back"),
+            ];
+            let area = centered(frame.area(), 50, 6);
+            frame.render_widget(Clear, area);
+            frame.render_widget(
+                Paragraph::new(text).block(Block::bordered().title("Finish")),
+                area,
+            );
+        }
+    }
+
+    /// Draws the selected image over the preview area, which the terminal
+    /// keeps showing until the area is drawn over.
+    fn show_preview(&self, preview: Preview) -> anyhow::Result<()> {
+        let area = self.preview_area;
+        if area.width == 0 || area.height == 0 {
+            return Ok(());
+        }
+        let path = self.groups[self.group].members[self.selected()];
+        let format = sniff_format(path)?.ok_or(anyhow::anyhow!("Not an image"))?;
+        let image = DynamicImage::ImageRgba8(open_image_path(path, format)?);
+
+        let (cell_width, cell_height) = match crossterm::terminal::window_size() {
+            Ok(size) if size.width > 0 && size.height > 0 && size.columns > 0 && size.rows > 0 => (
+                (size.width / size.columns) as u32,
+                (size.height / size.rows) as u32,
+            ),
+            _ => CELL_SIZE,
+        };
+        let image = image.thumbnail(
+            area.width as u32 * cell_width,
+            area.height as u32 * cell_height,
+        );
+
+        let mut stdout = io::stdout().lock();
+        queue!(stdout, MoveTo(area.x, area.y))?;
+        match preview {
+            Preview::Kitty => stdout.write_all(kitty(&image)?.as_bytes())?,
+            Preview::Sixel => stdout.write_all(sixel(&image.to_rgb8()).as_bytes())?,
+            Preview::None => (),
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal, preview: Preview) -> io::Result<Outcome> {
+        let mut shown = None;
+        loop {
+            let current = (self.group, self.selected(), self.confirming);
+            // Images aren't part of the screen as ratatui knows it, so it's
+            // cleared to get rid of the previous one.
+            let changed = preview != Preview::None && shown != Some(current);
+            if changed {
+                terminal.clear()?;
+            }
+            terminal.draw(|frame| self.draw(frame, preview))?;
+            if changed {
+                if !self.confirming {
+                    // An image that can't be shown leaves the area empty.
+                    self.show_preview(preview).ok();
+                }
+                shown = Some(current);
+            }
+
+            match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => {
+                    if let Some(outcome) = self.handle(key) {
+                        return Ok(outcome);
+                    }
+                }
+                Event::Resize(_, _) => shown = None,
+                _ => (),
+            }
+        }
+    }
+}
+
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    )
+}
+
+/// Encodes an image for the kitty graphics protocol, as a PNG sent in chunks.
+/// Responses from the terminal are turned off, since they would arrive as
+/// key presses.
+fn kitty(image: &DynamicImage) -> anyhow::Result<String> {
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageFormat::Png)?;
+    let encoded = STANDARD.encode(png.into_inner());
+
+    // Removes the previous image first.
+    let mut out = String::from("\x1b_Ga=d,q=2\x1b\\");
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(4096).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        let chunk = std::str::from_utf8(chunk).expect("Base64 is ASCII");
+        if i == 0 {
+            write!(out, "\x1b_Ga=T,f=100,q=2,m={};{}\x1b\\", more, chunk).unwrap();
+        } else {
+            write!(out, "\x1b_Gm={};{}\x1b\\", more, chunk).unwrap();
+        }
+    }
+    Ok(out)
+}
+
+fn push_run(out: &mut String, sixel: char, count: usize) {
+    if count > 3 {
+        write!(out, "!{}{}", count, sixel).unwrap();
+    } else {
+        out.extend(std::iter::repeat_n(sixel, count));
+    }
+}
+
+/// Encodes an image as sixels, with its colors reduced to a palette of 6
+/// levels of red, green and blue.
+fn sixel(image: &RgbImage) -> String {
+    let (width, height) = image.dimensions();
+    let level = |value: u8| (value as usize * 5 + 127) / 255;
+    let colors: Vec<usize> = image
+        .pixels()
+        .map(|pixel| level(pixel[0]) * 36 + level(pixel[1]) * 6 + level(pixel[2]))
+        .collect();
+
+    // Writing to a String can't fail.
+    let mut out = format!("\x1bPq\"1;1;{};{}", width, height);
+    for color in 0..216 {
+        write!(
+            out,
+            "#{};2;{};{};{}",
+            color,
+            color / 36 * 20,
+            color / 6 % 6 * 20,
+            color % 6 * 20
+        )
+        .unwrap();
+    }
+
+    // Each band is six rows high, drawn once for every color in it.
+    for top in (0..height).step_by(6) {
+        let rows = (height - top).min(6);
+        let color_at = |x: u32, y: u32| colors[(y * width + x) as usize];
+        let mut present: Vec<usize> = (top..top + rows)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| color_at(x, y))
+            .collect();
+        present.sort();
+        present.dedup();
+
+        for (i, color) in present.iter().enumerate() {
+            if i > 0 {
+                out.push('$');
+            }
+            write!(out, "#{}", color).unwrap();
+            let mut run: Option<(char, usize)> = None;
+            for x in 0..width {
+                let bits = (0..rows)
+                    .filter(|dy| color_at(x, top + dy) == *color)
+                    .fold(0, |bits, dy| bits | 1 << dy);
+                let sixel = char::from(63 + bits as u8);
+                match &mut run {
+                    Some((last, count)) if *last == sixel => *count += 1,
+                    _ => {
+                        if let Some((last, count)) = run {
+                            push_run(&mut out, last, count);
+                        }
+                        run = Some((sixel, 1));
+                    }
+                }
+            }
+            if let Some((last, count)) = run {
+                push_run(&mut out, last, count);
+            }
+        }
+        out.push('-');
+    }
+
+    out.push_str("\x1b\\");
+    out
+}
+
+/// Remembers the pairs of the groups marked as not being duplicates, so they
+/// aren't reported again.
+fn store_not_duplicates(
+    groups: &[Group],
+    decisions: &[Decisions],
+    db_path: &Path,
+) -> anyhow::Result<()> {
+    let pairs: Vec<(&Path, &Path)> = groups
+        .iter()
+        .zip(decisions)
+        .filter(|(_, decisions)| decisions.not_duplicate)
+        .flat_map(|(group, _)| group.pairs.iter().map(|(a, b, _, _)| (*a, *b)))
+        .collect();
+    if pairs.is_empty() {
+        return Ok(());
+    }
+    let mut conn = Connection::open(db_path)?;
+    ignored::store(&mut conn, &pairs, true)
+}
+
+/// Lets the user decide on each group in the terminal, then makes the changes
+/// with the action given to `review`. Groups marked as not being duplicates
+/// are remembered in the database, if there is one.
+pub fn main_review(groups: &[Group], args: &ReviewArgs, db_path: Option<&Path>) {
+    if groups.is_empty() {
+        eprintln!("No similar images found.");
+        return;
+    }
+
+    let mut review = Review::new(groups);
+    let mut terminal = match ratatui::try_init() {
+        Ok(terminal) => terminal,
+        Err(e) => {
+            eprintln!("Unable to start the review: {}", e);
+            exit(1);
+        }
+    };
+    let outcome = review.run(&mut terminal, args.preview);
+    ratatui::restore();
+
+    match outcome {
+        Ok(Outcome::Apply) => (),
+        Ok(Outcome::Quit) => {
+            eprintln!("Review ended without changes.");
+            return;
+        }
+        Err(e) => {
+            eprintln!("Review failed: {}", e);
+            exit(1);
+        }
+    }
+
+    if review
+        .decisions
+        .iter()
+        .any(|decisions| decisions.not_duplicate)
+    {
+        match db_path {
+            Some(db_path) => {
+                if let Err(e) = store_not_duplicates(groups, &review.decisions, db_path) {
+                    eprintln!(
+                        "Unable to remember the groups that aren't duplicates: {}",
+                        e
+                    );
+                }
+            }
+            None => {
+                eprintln!("Groups that aren't duplicates can't be remembered without the database.")
+            }
+        }
+    }
+
+    let (changes, notes) = review.changes();
+    for note in notes {
+        eprintln!("{}", note);
+    }
+    if changes.is_empty() {
+        eprintln!("Nothing to change.");
+    } else {
+        run_action(&changes, &args.action, true, args.journal.as_deref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn key(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn test_changes() {
+        let paths = [
+            Path::new("/photos/a.jpg"),
+            Path::new("/photos/b.jpg"),
+            Path::new("/photos/c.jpg"),
+            Path::new("/photos/d.jpg"),
+        ];
+        let groups = [
+            Group {
+                members: paths[..3].to_vec(),
+                pairs: Vec::new(),
+                keeper: None,
+            },
+            Group {
+                members: paths[2..].to_vec(),
+                pairs: Vec::new(),
+                keeper: Some(paths[2]),
+            },
+        ];
+        let mut review = Review::new(&groups);
+        assert!(review.changes().0.is_empty());
+
+        // Keep b and delete the rest, but c is kept in the second group.
+        review.list.select(Some(1));
+        review.handle(key('o'));
+        // Moves on to the second group, where d is deleted.
+        review.handle(KeyEvent::new(KeyCode::Right, KeyModifiers::NONE));
+        review.list.select(Some(1));
+        review.handle(key('d'));
+
+        let (changes, notes) = review.changes();
+        let members: Vec<_> = changes.iter().map(|group| group.members.clone()).collect();
+        assert_eq!(
+            members,
+            vec![vec![paths[1], paths[0]], vec![paths[2], paths[3]]]
+        );
+        assert!(notes.is_empty());
+
+        // Groups marked as not duplicates are left alone.
+        review.handle(key('x'));
+        assert_eq!(review.changes().0.len(), 1);
+    }
+
+    #[test]
+    fn test_sixel() {
+        let image = RgbImage::from_raw(2, 1, vec![0, 0, 0, 255, 0, 0]).unwrap();
+        let encoded = sixel(&image);
+        assert!(encoded.starts_with("\x1bPq\"1;1;2;1#0;2;0;0;0"));
+        assert!(encoded.ends_with("#0@?$#180?@-\x1b\\"));
+    }
+}
@@ -0,0 +1,161 @@
+use std::{io::Write, path::Path};
+
+use crate::{
+    action::plan,
+    cli::{Action, Script},
+    formatting::Group,
+    shared::path_to_bytes,
+};
+
+/// Quotes a path for a POSIX shell. Everything between single quotes is taken
+/// literally, including newlines and bytes that aren't valid UTF-8, so only a
+/// single quote needs care: it ends the quoting, is escaped and starts it
+/// again.
+fn shell_quote(bytes: &[u8]) -> Vec<u8> {
+    let mut quoted = Vec::with_capacity(bytes.len() + 2);
+    quoted.push(b'\'');
+    for byte in bytes {
+        if *byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(*byte);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// Writes text as comments, one per line, so that a path with a newline in it
+/// can't end the comment and be run as a command.
+fn push_comment(script: &mut Vec<u8>, text: &[u8]) {
+    for line in text.split(|byte| *byte == b'\n') {
+        script.extend_from_slice(b"# ");
+        script.extend_from_slice(line);
+        script.push(b'\n');
+    }
+}
+
+/// Quotes a path made absolute, so the script does the same wherever it's run
+/// from.
+fn quote_path(path: &Path) -> Vec<u8> {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    shell_quote(path_to_bytes(&path))
+}
+
+fn push_command(script: &mut Vec<u8>, words: &[&[u8]]) {
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            script.push(b' ');
+        }
+        script.extend_from_slice(word);
+    }
+}
+
+fn render(groups: &[Group], kind: &Script) -> Vec<u8> {
+    let action = match kind {
+        Script::Rm => Action::Delete,
+        Script::Mv(dir) => Action::Move(dir.clone()),
+        Script::Ln => Action::Hardlink,
+    };
+
+    let mut script = b"#!/bin/sh\n".to_vec();
+    push_comment(
+        &mut script,
+        b"Written by simagef. Check that each image kept is the one you want\n\
+          before running this.",
+    );
+
+    for (i, (group, steps)) in groups.iter().zip(plan(groups, &action)).enumerate() {
+        script.push(b'\n');
+        push_comment(&mut script, format!("Group {}", i + 1).as_bytes());
+        if let Some(keeper) = group.keeper {
+            let mut line = b"keep ".to_vec();
+            line.extend(quote_path(keeper));
+            push_comment(&mut script, &line);
+        }
+        for step in steps {
+            let step = match step {
+                Ok(step) => step,
+                Err(e) => {
+                    push_comment(&mut script, format!("skipped {}", e).as_bytes());
+                    continue;
+                }
+            };
+            let path = quote_path(step.path);
+            match &step.to {
+                Some(to) => {
+                    if let Some(parent) = to.parent() {
+                        let parent = quote_path(parent);
+                        push_command(&mut script, &[b"mkdir -p --", &parent, b"&& "]);
+                    }
+                    let to = quote_path(to);
+                    push_command(&mut script, &[b"mv --", &path, &to]);
+                }
+                None if matches!(action, Action::Hardlink) => {
+                    let keeper = quote_path(step.keeper);
+                    push_command(&mut script, &[b"ln -f --", &keeper, &path]);
+                }
+                None => push_command(&mut script, &[b"rm --", &path]),
+            }
+            script.push(b'\n');
+        }
+    }
+
+    script
+}
+
+/// Prints a shell script that removes, moves or hard links the images that
+/// aren't kept in each group, with the image kept noted above each block.
+pub fn print_script(groups: &[Group], kind: &Script) {
+    let mut stdout = std::io::stdout().lock();
+    stdout
+        .write_all(&render(groups, kind))
+        .expect("Unable to write to stdout");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn quote(path: &str) -> String {
+        String::from_utf8(shell_quote(path.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(quote("/photos/a.jpg"), "'/photos/a.jpg'");
+        assert_eq!(quote("/photos/a b.jpg"), "'/photos/a b.jpg'");
+        assert_eq!(quote("/photos/it's.jpg"), "'/photos/it'\\''s.jpg'");
+        assert_eq!(
+            quote("/photos/\"$(rm -rf ~)\".jpg"),
+            "'/photos/\"$(rm -rf ~)\".jpg'"
+        );
+        assert_eq!(quote("/photos/new\nline.jpg"), "'/photos/new\nline.jpg'");
+        assert_eq!(shell_quote(b"/photos/\xff.jpg"), b"'/photos/\xff.jpg'");
+    }
+
+    #[test]
+    fn test_push_comment() {
+        let mut script = Vec::new();
+        push_comment(&mut script, b"keep '/photos/new\nrm -rf ~'");
+        assert_eq!(script, b"# keep '/photos/new\n# rm -rf ~'\n");
+    }
+
+    #[test]
+    fn test_render_absolute_paths() {
+        let (a, b) = (Path::new("photos/a.jpg"), Path::new("photos/b.jpg"));
+        let groups = vec![Group {
+            members: vec![a, b],
+            pairs: Vec::new(),
+            keeper: Some(a),
+        }];
+        let cwd = std::env::current_dir().unwrap();
+        let script = String::from_utf8(render(&groups, &Script::Rm)).unwrap();
+        assert!(script.ends_with(&format!(
+            "# keep '{}/photos/a.jpg'\nrm -- '{}/photos/b.jpg'\n",
+            cwd.display(),
+            cwd.display()
+        )));
+    }
+}
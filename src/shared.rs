@@ -1,9 +1,15 @@
+#[cfg(not(feature = "no-exec"))]
+use std::process::Command;
 use std::{
-    collections::{HashMap, HashSet},
-    process::Command,
+    collections::HashMap,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
 };
 
-use crate::cli::Cli;
+use crate::cli::{Cli, Cluster};
+use crate::cluster::{cluster, Edge};
+use crate::formatting::{Group, Match, Pair, Printer};
 
 #[derive(Debug)]
 pub struct CompareTask {
@@ -18,70 +24,158 @@ pub struct Pairing {
     pub score: f64,
 }
 
-pub fn make_groups_and_exec(
-    name_map: &[String],
-    pairings: Vec<Pairing>,
+/// A matched pair as indices into the list of images, with its score and how
+/// it matched.
+pub type IndexPair = (usize, usize, f64, Match);
+
+/// Groups the matched pairs with the chosen clustering method, or takes the
+/// groups made around each query image when comparing a query set against a
+/// reference set.
+pub fn arrange_groups<'a>(
+    name_map: &'a [PathBuf],
+    pairs: &[IndexPair],
+    query_groups: Option<Vec<Vec<usize>>>,
+    method: Cluster,
+    threshold: f64,
+) -> Vec<Group<'a>> {
+    let cross_set = query_groups.is_some();
+    let mut groups = match query_groups {
+        Some(groups) => groups,
+        None => {
+            let edges: Vec<Edge> = pairs
+                .iter()
+                .map(|(a, b, score, _)| (*a, *b, *score))
+                .collect();
+            cluster(method, &edges, threshold)
+        }
+    };
+    // Groups come out of the graph in no particular order, so they're sorted by
+    // path to keep the output, and the group ids in it, the same between runs.
+    // Query groups keep their query first and their references by score, and
+    // star groups keep their representative first.
+    if !cross_set {
+        let skip = if method == Cluster::Star { 1 } else { 0 };
+        for group in &mut groups {
+            group[skip..].sort_by(|a, b| name_map[*a].cmp(&name_map[*b]));
+        }
+    }
+    groups.sort_by(|a, b| name_map[a[0]].cmp(&name_map[b[0]]));
+
+    // Each pair belongs to the group of its first image. Query groups only
+    // claim their query, since a reference can appear in several of them.
+    // Other methods can split a pair between groups, in which case it's left
+    // out.
+    let mut group_of: HashMap<usize, usize> = HashMap::new();
+    for (i, group) in groups.iter().enumerate() {
+        let members = if cross_set { &group[..1] } else { &group[..] };
+        for member in members {
+            group_of.insert(*member, i);
+        }
+    }
+    let mut group_pairs: Vec<Vec<Pair>> = vec![Vec::new(); groups.len()];
+    for (index1, index2, score, kind) in pairs {
+        let mut a = name_map[*index1].as_path();
+        let mut b = name_map[*index2].as_path();
+        if !cross_set && b < a {
+            std::mem::swap(&mut a, &mut b);
+        }
+        if let Some(group) = group_of.get(index1) {
+            if cross_set || Some(group) == group_of.get(index2) {
+                group_pairs[*group].push((a, b, *score, *kind));
+            }
+        }
+    }
+
+    groups
+        .into_iter()
+        .zip(group_pairs)
+        .map(|(group, mut pairs)| {
+            pairs.sort_by(|a, b| {
+                b.2.total_cmp(&a.2)
+                    .then_with(|| (a.0, a.1).cmp(&(b.0, b.1)))
+            });
+            Group {
+                members: group
+                    .iter()
+                    .map(|index| name_map[*index].as_path())
+                    .collect(),
+                pairs,
+                keeper: None,
+            }
+        })
+        .collect()
+}
+
+/// The pairs that `arrange_groups` left out because their images ended up in
+/// different groups, or in none, best first.
+pub fn pairs_between_groups<'a>(
+    name_map: &'a [PathBuf],
+    pairs: &[IndexPair],
+    groups: &[Group],
+) -> Vec<Pair<'a>> {
+    let group_of: HashMap<&Path, usize> = groups
+        .iter()
+        .enumerate()
+        .flat_map(|(i, group)| group.members.iter().map(move |member| (*member, i)))
+        .collect();
+    let mut between: Vec<Pair> = pairs
+        .iter()
+        .map(|(index1, index2, score, kind)| {
+            let a = name_map[*index1].as_path();
+            let b = name_map[*index2].as_path();
+            (a.min(b), a.max(b), *score, *kind)
+        })
+        .filter(|(a, b, _, _)| match (group_of.get(a), group_of.get(b)) {
+            (Some(group_a), Some(group_b)) => group_a != group_b,
+            _ => true,
+        })
+        .collect();
+    between.sort_by(|a, b| {
+        b.2.total_cmp(&a.2)
+            .then_with(|| (a.0, a.1).cmp(&(b.0, b.1)))
+    });
+    between
+}
+
+#[cfg_attr(feature = "no-exec", allow(unused_variables))]
+pub fn print_groups_and_exec(
+    groups: &[Group],
     executable: &Option<(&str, Vec<&str>)>,
+    printer: &mut Printer,
 ) {
-    let groups = make_groups(pairings);
     for group in groups {
-        let name_group: Vec<String> = group.iter().map(|index| name_map[*index].clone()).collect();
-        let line = name_group.join(" ");
-        println!("{}", line);
+        printer.group(group);
         #[cfg(not(feature = "no-exec"))]
         if let Some((program, args)) = &executable {
             Command::new(program)
                 .args(args)
-                .args(name_group)
+                .args(&group.members)
                 .output()
                 .expect("Unable to run program provided");
         }
     }
 }
 
-pub fn make_groups(pairs: Vec<Pairing>) -> Vec<Vec<usize>> {
-    let mut graph: HashMap<usize, Vec<usize>> = HashMap::new();
-
-    // Build the graph
-    for pair in pairs.iter() {
-        // let (node1, node2) = *pair;
-        let node1 = pair.index1;
-        let node2 = pair.index2;
-        graph.entry(node1).or_default().push(node2);
-        graph.entry(node2).or_default().push(node1);
-    }
-
-    let mut visited: HashSet<usize> = HashSet::new();
-    let mut groups: Vec<Vec<usize>> = Vec::new();
-
-    // Perform DFS to find connected components
-    fn dfs(
-        node: usize,
-        graph: &HashMap<usize, Vec<usize>>,
-        visited: &mut HashSet<usize>,
-        mut group: Vec<usize>,
-    ) -> Vec<usize> {
-        visited.insert(node);
-        group.push(node);
-        if let Some(neighbors) = graph.get(&node) {
-            for neighbor in neighbors.iter() {
-                if !visited.contains(neighbor) {
-                    group = dfs(*neighbor, graph, visited, group);
-                }
-            }
-        }
-        group
-    }
-
-    for node in graph.keys() {
-        if !visited.contains(node) {
-            let mut group: Vec<usize> = Vec::new();
-            group = dfs(*node, &graph, &mut visited, group);
-            groups.push(group);
-        }
+/// Prints a pair and runs the program given with `--exec` on it.
+#[cfg_attr(feature = "no-exec", allow(unused_variables))]
+pub fn print_pair_and_exec(
+    a: &Path,
+    b: &Path,
+    score: f64,
+    kind: Match,
+    executable: &Option<(&str, Vec<&str>)>,
+    printer: &mut Printer,
+) {
+    printer.pair(a, b, score, kind);
+    #[cfg(not(feature = "no-exec"))]
+    if let Some((program, args)) = &executable {
+        Command::new(program)
+            .args(args)
+            .arg(a)
+            .arg(b)
+            .output()
+            .expect("Unable to run executable provided");
     }
-
-    groups
 }
 
 #[cfg(not(feature = "no-exec"))]
@@ -95,6 +189,84 @@ pub fn get_executable(cli: &Cli) -> Option<(&str, Vec<&str>)> {
 }
 
 #[cfg(feature = "no-exec")]
-pub fn get_executable(cli: &Cli) -> Option<(&str, Vec<&str>)> {
+pub fn get_executable(_cli: &Cli) -> Option<(&str, Vec<&str>)> {
     None
 }
+
+/// A BLAKE3 hash of the content of a file.
+pub type Hash = [u8; 32];
+
+/// Hashes the content of a file, so it is recognized after it has been renamed
+/// or moved.
+pub fn content_hash(path: &Path) -> io::Result<Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// The bytes of a path, exactly as the OS gave them to us.
+pub fn path_to_bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_encoded_bytes()
+}
+
+/// Reverses `path_to_bytes`.
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+/// Reverses `path_to_bytes`. Paths that aren't valid Unicode can't be
+/// reconstructed safely outside of Unix, so they are converted lossily.
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_pairs_between_groups() {
+        let name_map: Vec<PathBuf> = ["/a.png", "/b.png", "/c.png", "/d.png"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let pairs = [
+            (0, 1, 0.95, Match::Perceptual),
+            (2, 1, 0.93, Match::Perceptual),
+            (0, 2, 0.91, Match::Perceptual),
+            (3, 2, 0.92, Match::Pixel),
+        ];
+        // 2 and 3 match better than 2 matches the worse of 0 and 1.
+        let groups = arrange_groups(&name_map, &pairs, None, Cluster::CompleteLinkage, 0.9);
+        let members: Vec<&[&Path]> = groups.iter().map(|group| &group.members[..]).collect();
+        assert_eq!(
+            members,
+            vec![
+                &[Path::new("/a.png"), Path::new("/b.png")],
+                &[Path::new("/c.png"), Path::new("/d.png")],
+            ]
+        );
+        assert_eq!(
+            pairs_between_groups(&name_map, &pairs, &groups),
+            vec![
+                (
+                    Path::new("/b.png"),
+                    Path::new("/c.png"),
+                    0.93,
+                    Match::Perceptual
+                ),
+                (
+                    Path::new("/a.png"),
+                    Path::new("/c.png"),
+                    0.91,
+                    Match::Perceptual
+                ),
+            ]
+        );
+    }
+}
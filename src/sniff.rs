@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    sync::Mutex,
+};
+
+use image::ImageFormat;
+
+/// Long enough to cover the magic bytes of every format the image crate knows.
+const SNIFF_LEN: u64 = 16;
+
+/// Guesses the format of a file from its first few bytes, without reading the
+/// rest of it. TGA has no magic bytes, so it is recognized by extension only.
+pub fn sniff_format(path: &Path) -> io::Result<Option<ImageFormat>> {
+    let mut buf = Vec::with_capacity(SNIFF_LEN as usize);
+    File::open(path)?.take(SNIFF_LEN).read_to_end(&mut buf)?;
+
+    match image::guess_format(&buf) {
+        Ok(format) => Ok(Some(format)),
+        Err(_) => Ok(ImageFormat::from_path(path)
+            .ok()
+            .filter(|format| *format == ImageFormat::Tga)),
+    }
+}
+
+#[derive(Default)]
+struct FormatCounts {
+    formats: HashMap<ImageFormat, u64>,
+    not_images: u64,
+    mislabeled: u64,
+}
+
+/// Tallies the formats found by the signature threads for the summary printed
+/// at the end of a run.
+#[derive(Default)]
+pub struct SniffStats {
+    counts: Mutex<FormatCounts>,
+}
+
+impl SniffStats {
+    /// Sniffs a file, records its format and warns if its extension names a
+    /// different format than its content. Returns `None` for non-images.
+    pub fn check(&self, path: &Path) -> io::Result<Option<ImageFormat>> {
+        let format = sniff_format(path)?;
+        let labeled = ImageFormat::from_path(path).ok();
+        let mislabeled = matches!((format, labeled), (Some(f), Some(l)) if f != l);
+
+        if mislabeled {
+            eprintln!(
+                "{}: Extension suggests {:?}, but the content is {:?}",
+                path.display(),
+                labeled.unwrap(),
+                format.unwrap()
+            );
+        }
+
+        let mut counts = self.counts.lock().expect("Unable to lock format counts");
+        match format {
+            Some(format) => *counts.formats.entry(format).or_default() += 1,
+            None => counts.not_images += 1,
+        }
+        if mislabeled {
+            counts.mislabeled += 1;
+        }
+
+        Ok(format)
+    }
+
+    /// One line listing how many files of each format were seen, or `None` if
+    /// no files were checked.
+    pub fn summary(&self) -> Option<String> {
+        let counts = self.counts.lock().expect("Unable to lock format counts");
+
+        let mut formats: Vec<(&ImageFormat, &u64)> = counts.formats.iter().collect();
+        formats
+            .sort_by_key(|(format, count)| (std::cmp::Reverse(**count), format!("{:?}", format)));
+        let mut parts: Vec<String> = formats
+            .iter()
+            .map(|(format, count)| format!("{:?}: {}", format, count))
+            .collect();
+
+        if counts.not_images > 0 {
+            parts.push(format!("not an image: {}", counts.not_images));
+        }
+        if counts.mislabeled > 0 {
+            parts.push(format!("wrong extension: {}", counts.mislabeled));
+        }
+
+        if parts.is_empty() {
+            None
+        } else {
+            Some(format!("Files checked - {}", parts.join(", ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image::RgbaImage::new(2, 2)
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_sniff_format() {
+        let dir = std::env::temp_dir().join(format!("simagef-sniff-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, bytes: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, bytes).unwrap();
+            path
+        };
+        let png = encode(ImageFormat::Png);
+        let tga = encode(ImageFormat::Tga);
+
+        // The content wins over the extension.
+        let mislabeled = write("photo.jpg", &png);
+        assert_eq!(sniff_format(&mislabeled).unwrap(), Some(ImageFormat::Png));
+        // TGA has no magic bytes, so only its extension gives it away.
+        assert_eq!(
+            sniff_format(&write("a.tga", &tga)).unwrap(),
+            Some(ImageFormat::Tga)
+        );
+        assert_eq!(sniff_format(&write("a.bin", &tga)).unwrap(), None);
+        // Other extensions aren't trusted without the content to back them.
+        let text = write("notes.jpg", b"not an image");
+        assert_eq!(sniff_format(&text).unwrap(), None);
+
+        let stats = SniffStats::default();
+        stats.check(&mislabeled).unwrap();
+        stats.check(&text).unwrap();
+        assert_eq!(
+            stats.summary().unwrap(),
+            "Files checked - Png: 1, not an image: 1, wrong extension: 1"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+#[cfg(not(feature = "no-exec"))]
+use std::process::Command;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::exit,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::RecvTimeoutError;
+use notify::{EventKind, RecursiveMode, Watcher};
+use rusqlite::Connection;
+
+use crate::{
+    cli::{Cli, ContentHash, WatchArgs},
+    database, fetch_signature,
+    filter::InputFilter,
+    formatting::{Match, Printer},
+    ignored::IgnoredPairs,
+    image_match_rs::cosine_similarity,
+    index::PersistentIndex,
+    shared::{self, get_executable},
+    sniff::SniffStats,
+    FetchOptions, Fetched, SigFetchError,
+};
+
+/// Storing the index means serializing all of it, so it isn't done after
+/// every file.
+const SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Computes the signature of a new file, finds its matches among the
+/// signatures in the database and then adds it to the database and index.
+fn process_file(
+    path: &Path,
+    db_conn: &Option<Connection>,
+    index: &mut PersistentIndex,
+    threshold: f64,
+    sniff_stats: &SniffStats,
+    ignored: &IgnoredPairs,
+    options: FetchOptions,
+) -> anyhow::Result<Vec<(PathBuf, f64)>> {
+    let conn = db_conn.as_ref().expect("Watch mode needs a database");
+    let canonical = std::fs::canonicalize(path)?;
+    let stat = std::fs::metadata(&canonical)?;
+    let Fetched {
+        signature,
+        pixel_hash,
+        ..
+    } = fetch_signature(&canonical, db_conn, None, sniff_stats, options)?;
+
+    let mut matches = Vec::new();
+    for candidate in index.candidates(&signature)? {
+        if candidate == canonical {
+            continue;
+        }
+        if let Some(entry) = database::fetch_stored(conn, candidate)? {
+            let score = cosine_similarity(&signature, &entry.signature);
+            if score > threshold && !ignored.contains(&canonical, candidate) {
+                matches.push((entry.path, score));
+            }
+        }
+    }
+    matches.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let hash = match options.content_hash {
+        ContentHash::Never => None,
+        _ => shared::content_hash(&canonical).ok(),
+    };
+    database::insert(
+        conn,
+        &canonical,
+        &stat,
+        hash.as_ref(),
+        pixel_hash.as_ref(),
+        &signature,
+    )?;
+    index.sync(conn)?;
+
+    Ok(matches)
+}
+
+/// Waits for files to be created or modified in the watched directories. A
+/// file is compared once it has gone `--settle` milliseconds without further
+/// changes. Each match is printed as soon as it is found, with the score first,
+/// and `--exec` is run with the new file followed by its matches. Runs until
+/// interrupted, then stores the index and ends the output.
+pub fn main_watch(cli: &Cli, args: &WatchArgs) {
+    let db_path = database::database_path(cli).expect("Unable to figure out database path");
+    let mut conn = database::open(&db_path);
+
+    let filter = InputFilter::from_cli(cli).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+
+    let threshold_u8 = cli.threshold.unwrap_or(90);
+    let threshold = f64::from(threshold_u8) * 0.01;
+
+    let mut index = PersistentIndex::open(&conn, threshold_u8).expect("Unable to load index");
+    if index.sync(&conn).expect("Unable to update index") > 0 {
+        index.save(&mut conn).expect("Unable to store index");
+    }
+    let ignored = IgnoredPairs::load(&conn).expect("Unable to load ignored pairs");
+    let mut db_conn = Some(conn);
+    let mut unsaved = false;
+
+    let (event_tx, event_rx) = crossbeam::channel::unbounded();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        event_tx.send(event).ok();
+    })
+    .expect("Unable to start watching for changes");
+    for dir in &args.dirs {
+        if let Err(e) = watcher.watch(dir, RecursiveMode::Recursive) {
+            eprintln!("{}: {}", dir.display(), e);
+            exit(1);
+        }
+    }
+
+    #[cfg_attr(feature = "no-exec", allow(unused_variables))]
+    let executable = get_executable(cli);
+    let sniff_stats = SniffStats::default();
+    let mut printer = Printer::new(cli.format).with_scores();
+    let settle = Duration::from_millis(args.settle);
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    let mut last_save = Instant::now();
+
+    // Checked between events, which come in at least every 250ms.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, interrupted.clone())
+            .expect("Unable to handle interrupts");
+    }
+
+    while !interrupted.load(Ordering::Relaxed) {
+        match event_rx.recv_timeout(settle.min(Duration::from_millis(250))) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        pending.insert(path, Instant::now());
+                    }
+                }
+            }
+            Ok(Err(e)) => eprintln!("{}", e),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let settled: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, changed)| changed.elapsed() >= settle)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in settled {
+            pending.remove(&path);
+            if !path.is_file() || !filter.accepts_file(&path) {
+                continue;
+            }
+
+            match process_file(
+                &path,
+                &db_conn,
+                &mut index,
+                threshold,
+                &sniff_stats,
+                &ignored,
+                FetchOptions::from_cli(cli),
+            ) {
+                Ok(matches) => {
+                    unsaved = true;
+                    for (matched, score) in &matches {
+                        printer.pair(&path, matched, *score, Match::Perceptual);
+                    }
+                    #[cfg(not(feature = "no-exec"))]
+                    if let Some((program, args)) = &executable {
+                        if !matches.is_empty() {
+                            Command::new(program)
+                                .args(args)
+                                .arg(&path)
+                                .args(matches.iter().map(|(matched, _)| matched))
+                                .output()
+                                .expect("Unable to run executable provided");
+                        }
+                    }
+                }
+                Err(e) => {
+                    if !matches!(e.downcast_ref(), Some(SigFetchError::NotAnImage)) {
+                        eprintln!("{}: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+
+        if unsaved && last_save.elapsed() >= SAVE_INTERVAL {
+            if let Err(e) = index.save(db_conn.as_mut().unwrap()) {
+                eprintln!("Unable to store index: {}", e);
+            }
+            unsaved = false;
+            last_save = Instant::now();
+        }
+    }
+
+    if unsaved {
+        if let Err(e) = index.save(db_conn.as_mut().unwrap()) {
+            eprintln!("Unable to store index: {}", e);
+        }
+    }
+    printer.finish();
+}